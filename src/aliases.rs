@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use toml::Table;
+
+use crate::matcher::split_steps;
+use crate::node_kind::NodeKind;
+
+/// Named pattern fragments from the `[aliases]` table. A step that is exactly the name of an alias
+/// is replaced with its steps, which can use other aliases in turn.
+#[derive(Debug, Default)]
+pub struct Aliases<'a> {
+    aliases: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Aliases<'a> {
+    pub fn from_toml(table: &'a Table) -> anyhow::Result<Self> {
+        let mut aliases = HashMap::new();
+        for (name, value) in table {
+            let toml::Value::String(fragment) = value else {
+                bail!("Found {value} as alias \"{name}\", expected a string");
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                bail!("Alias \"{name}\" can only contain letters, digits and _");
+            }
+            if NodeKind::ALL.iter().any(|kind| kind.name() == name) {
+                bail!("Alias \"{name}\" has the name of a node kind");
+            }
+            aliases.insert(name.as_str(), fragment.as_str());
+        }
+
+        let aliases = Self { aliases };
+        //Expanding everything up front finds cycles, even in aliases nothing uses
+        for name in table.keys() {
+            aliases.expand_into(name, &mut vec![], &mut vec![])?;
+        }
+        Ok(aliases)
+    }
+
+    /// `pattern` with every alias in it replaced, recursively
+    pub fn expand(&self, pattern: &'a str) -> anyhow::Result<String> {
+        let mut steps = vec![];
+        for step in split_steps(pattern)? {
+            self.expand_step(step, &mut vec![], &mut steps)?;
+        }
+        Ok(steps.join(" "))
+    }
+
+    fn expand_step(
+        &self,
+        step: &'a str,
+        using: &mut Vec<&'a str>,
+        output: &mut Vec<&'a str>,
+    ) -> anyhow::Result<()> {
+        if let Some((name, _)) = self.aliases.get_key_value(step) {
+            return self.expand_into(name, using, output);
+        }
+        //Aliases stand for whole runs of steps, so a modifier on one has no single step to go to
+        let name = step.trim_start_matches('^');
+        let name = name.split(['{', '[']).next().unwrap_or(name);
+        if name != step && self.aliases.contains_key(name) {
+            bail!("Alias \"{name}\" can only be used as a whole step, not in \"{step}\"");
+        }
+        output.push(step);
+        Ok(())
+    }
+
+    fn expand_into(
+        &self,
+        name: &'a str,
+        using: &mut Vec<&'a str>,
+        output: &mut Vec<&'a str>,
+    ) -> anyhow::Result<()> {
+        if let Some(start) = using.iter().position(|other| *other == name) {
+            let cycle = using[start..].join(" -> ");
+            bail!("Alias \"{name}\" uses itself: {cycle} -> {name}");
+        }
+        let fragment = self.aliases[name];
+        using.push(name);
+        for step in split_steps(fragment)? {
+            self.expand_step(step, using, output)?;
+        }
+        using.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(toml: &str) -> anyhow::Result<Aliases<'static>> {
+        let table = Box::leak(Box::new(toml.parse::<Table>().unwrap()));
+        Aliases::from_toml(table)
+    }
+
+    #[test]
+    fn aliases_expand_recursively() {
+        let aliases = aliases(
+            "TOK = \"^WhiteSpace Locate\"\n\
+             DATA = \"ModuleOrGenerateItemDeclaration DataDeclaration\"\n\
+             VARIABLE = \"DATA VariableIdentifier TOK\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            aliases.expand("VARIABLE").unwrap(),
+            "ModuleOrGenerateItemDeclaration DataDeclaration VariableIdentifier ^WhiteSpace Locate"
+        );
+        assert_eq!(
+            aliases.expand("Keyword  Locate[=\"a b\"]").unwrap(),
+            "Keyword Locate[=\"a b\"]"
+        );
+        assert!(aliases.expand("^TOK").is_err());
+        assert!(aliases.expand("DATA{2}").is_err());
+    }
+
+    #[test]
+    fn aliases_reject_cycles() {
+        let error = aliases("A = \"Keyword B\"\nB = \"C\"\nC = \"A Locate\"\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Alias \"A\" uses itself: A -> B -> C -> A"
+        );
+
+        assert!(aliases("Locate = \"Keyword\"").is_err());
+        assert!(aliases("TOK = 1").is_err());
+    }
+}
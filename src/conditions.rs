@@ -0,0 +1,203 @@
+use anyhow::bail;
+use toml::{Table, Value};
+
+/// One link of a chain of conditions
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition<'a> {
+    //The groups the text of an item is looked up in, the condition always holds if there are none
+    groups: Vec<&'a str>,
+    //Whether the condition holds when the text is defined in none of `groups`, instead of any
+    negated: bool,
+    //The group the item goes to when the condition holds
+    group: &'a str,
+}
+
+/// Decides which group the items of a conditional group go to, from the groups their text is
+/// defined in. The conditions are tried in order and the first that holds picks the group, items
+/// none of them hold for are dropped.
+///
+/// A group configures them with one of
+/// - `ifDefined = "A"` or `["A", "B"]`, keeping items defined in any of the groups
+/// - `ifNotDefined`, the same but keeping items defined in none of them
+/// - `[[<group>.when]]` tables, each with `defined_in` or `not_defined_in` and the `group` to go
+///   to, itself by default. A table with neither always holds.
+///
+/// and `orElse = "C"` for where items go when nothing holds.
+#[derive(Debug, Clone)]
+pub struct Conditions<'a> {
+    conditions: Vec<Condition<'a>>,
+}
+
+impl<'a> Conditions<'a> {
+    /// The conditions of group `name`, configured in `table`, or `None` if it has none and all its
+    /// items are kept as they are
+    pub fn from_toml(name: &'a str, table: &'a Table) -> anyhow::Result<Option<Self>> {
+        let mut conditions = vec![];
+        match (
+            table.get("ifDefined"),
+            table.get("ifNotDefined"),
+            table.get("when"),
+        ) {
+            (None, None, None) => (),
+            (Some(groups), None, None) => conditions.push(Condition {
+                groups: group_list(groups, "ifDefined", name)?,
+                negated: false,
+                group: name,
+            }),
+            (None, Some(groups), None) => conditions.push(Condition {
+                groups: group_list(groups, "ifNotDefined", name)?,
+                negated: true,
+                group: name,
+            }),
+            (None, None, Some(Value::Array(chain))) => {
+                for link in chain {
+                    let Value::Table(link) = link else {
+                        bail!("Found {link} in \'when\' of group {name}, expected a table");
+                    };
+                    conditions.push(when_condition(link, name)?);
+                }
+            }
+            (None, None, Some(other)) => {
+                bail!("Found {other} as \'when\' of group {name}, expected an array of tables")
+            }
+            _ => bail!(
+                "Group {name} can only have one of \'ifDefined\', \'ifNotDefined\' and \'when\'"
+            ),
+        }
+
+        match table.get("orElse") {
+            Some(Value::String(group)) if !conditions.is_empty() => conditions.push(Condition {
+                groups: vec![],
+                negated: false,
+                group,
+            }),
+            Some(Value::String(_)) => {
+                bail!(
+                    "\'orElse\' of group {name} needs \'ifDefined\', \'ifNotDefined\' or \'when\'"
+                )
+            }
+            Some(other) => bail!("Found {other} as \'orElse\' of group {name}"),
+            None => (),
+        }
+
+        Ok((!conditions.is_empty()).then_some(Self { conditions }))
+    }
+
+    /// The group an item goes to, given whether its text is defined in a group. `None` if it's
+    /// dropped.
+    pub fn group(&self, defined_in: impl Fn(&'a str) -> bool) -> Option<&'a str> {
+        self.conditions
+            .iter()
+            .find(|condition| {
+                let defined = condition.groups.iter().any(|group| defined_in(group));
+                condition.groups.is_empty() || defined != condition.negated
+            })
+            .map(|condition| condition.group)
+    }
+
+    /// Every group the text of an item is looked up in
+    pub fn predicates(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.conditions
+            .iter()
+            .flat_map(|condition| condition.groups.iter().copied())
+    }
+
+    /// Every group an item can go to, in order
+    pub fn targets(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.conditions.iter().map(|condition| condition.group)
+    }
+}
+
+fn when_condition<'a>(link: &'a Table, name: &'a str) -> anyhow::Result<Condition<'a>> {
+    if let Some(key) = link
+        .keys()
+        .find(|key| !["defined_in", "not_defined_in", "group"].contains(&key.as_str()))
+    {
+        bail!("Unknown key \"{key}\" in \'when\' of group {name}");
+    }
+    let group = match link.get("group") {
+        Some(Value::String(group)) => group.as_str(),
+        Some(other) => bail!("Found {other} as \'group\' in \'when\' of group {name}"),
+        None => name,
+    };
+    let (groups, negated) = match (link.get("defined_in"), link.get("not_defined_in")) {
+        (Some(groups), None) => (group_list(groups, "defined_in", name)?, false),
+        (None, Some(groups)) => (group_list(groups, "not_defined_in", name)?, true),
+        (None, None) => (vec![], false),
+        (Some(_), Some(_)) => {
+            bail!(
+                "A \'when\' of group {name} can't have both \'defined_in\' and \'not_defined_in\'"
+            )
+        }
+    };
+    Ok(Condition {
+        groups,
+        negated,
+        group,
+    })
+}
+
+/// The groups in `value`, a single group or a list of them, given as `key` of group `name`
+fn group_list<'a>(value: &'a Value, key: &str, name: &str) -> anyhow::Result<Vec<&'a str>> {
+    let groups = match value {
+        Value::String(group) => vec![group.as_str()],
+        Value::Array(list) => list
+            .iter()
+            .map(|group| match group {
+                Value::String(group) => Ok(group.as_str()),
+                other => bail!("Found {other} in \'{key}\' of group {name}, expected a group"),
+            })
+            .collect::<anyhow::Result<_>>()?,
+        other => bail!("Found {other} as \'{key}\' of group {name}, expected a group or a list"),
+    };
+    if groups.is_empty() {
+        bail!("\'{key}\' of group {name} can't be empty");
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conditions(toml: &str) -> anyhow::Result<Option<Conditions<'static>>> {
+        let table = Box::leak(Box::new(toml.parse::<Table>().unwrap()));
+        Conditions::from_toml("denshiIdentifier", table)
+    }
+
+    #[test]
+    fn conditions_pick_the_first_that_holds() {
+        let chain = conditions(
+            "[[when]]\ndefined_in = \"denshiParameterDefinition\"\ngroup = \"denshiParameterUse\"\n\
+             [[when]]\ndefined_in = [\"denshiVariableDefinition\", \"denshiPortDefinition\"]\n\
+             [[when]]\nnot_defined_in = \"denshiEnumDefinition\"\ngroup = \"denshiUndefined\"\n",
+        )
+        .unwrap()
+        .unwrap();
+        let group = |defined: &[&str]| chain.group(|group| defined.contains(&group));
+
+        assert_eq!(
+            group(&["denshiParameterDefinition", "denshiPortDefinition"]),
+            Some("denshiParameterUse")
+        );
+        assert_eq!(group(&["denshiPortDefinition"]), Some("denshiIdentifier"));
+        assert_eq!(group(&[]), Some("denshiUndefined"));
+        assert_eq!(group(&["denshiEnumDefinition"]), None);
+
+        let not_defined = conditions("ifNotDefined = [\"A\", \"B\"]\norElse = \"C\"\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(not_defined.group(|_| false), Some("denshiIdentifier"));
+        assert_eq!(not_defined.group(|group| group == "B"), Some("C"));
+    }
+
+    #[test]
+    fn conditions_reject_mixed_kinds() {
+        assert!(conditions("").unwrap().is_none());
+        assert!(conditions("ifDefined = \"A\"\nifNotDefined = \"B\"\n").is_err());
+        assert!(conditions("orElse = \"A\"\n").is_err());
+        assert!(conditions("ifDefined = []\n").is_err());
+        assert!(conditions("[[when]]\ndefined_in = \"A\"\nnot_defined_in = \"B\"\n").is_err());
+        assert!(conditions("[[when]]\ndefinedIn = \"A\"\n").is_err());
+    }
+}
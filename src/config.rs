@@ -0,0 +1,271 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use anyhow::Context;
+use toml::{Table, Value};
+
+/// The config used when none is given, the one shipped with the parser
+pub const DEFAULT_CONFIG: &str = include_str!("../denshi-parser-config.toml");
+
+/// Name of the project config, looked for in the directory of the code and every one above it
+pub const PROJECT_CONFIG: &str = ".denshi.toml";
+
+/// The config to highlight code with. The config at `base`, or `DEFAULT_CONFIG` without one,
+/// comes first, then the user config in `$XDG_CONFIG_HOME/denshi/config.toml`, then the
+/// `.denshi.toml` closest to `code_dir`, each merged over the ones before. Without a `code_dir`
+/// only the base config is read.
+pub fn load(base: Option<&Path>, code_dir: Option<&Path>) -> anyhow::Result<Table> {
+    let mut config = match base {
+        Some(base) => read_config(base)?,
+        None => DEFAULT_CONFIG
+            .parse::<Table>()
+            .context("Invalid default config")?,
+    };
+    let Some(code_dir) = code_dir else {
+        return Ok(config);
+    };
+    for layer in [user_config(), project_config(code_dir)]
+        .into_iter()
+        .flatten()
+    {
+        merge(&mut config, read_config(&layer)?)?;
+    }
+    Ok(config)
+}
+
+/// Reads the config at `path`, with the configs it lists in `include` merged in below it, in
+/// order. Included paths are relative to the config including them.
+pub fn read_config(path: &Path) -> anyhow::Result<Table> {
+    read_including(path, &mut vec![])
+}
+
+fn read_including(path: &Path, including: &mut Vec<PathBuf>) -> anyhow::Result<Table> {
+    let canonical = fs::canonicalize(path)
+        .with_context(|| format!("Could not read config {}", path.display()))?;
+    if let Some(start) = including.iter().position(|other| *other == canonical) {
+        let cycle = including[start..]
+            .iter()
+            .chain([&canonical])
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        bail!("Config includes itself: {cycle}");
+    }
+
+    let mut table = fs::read_to_string(path)
+        .with_context(|| format!("Could not read config {}", path.display()))?
+        .parse::<Table>()
+        .with_context(|| format!("Invalid config {}", path.display()))?;
+    let includes = match table.remove("include") {
+        Some(Value::Array(includes)) => includes,
+        Some(other) => bail!(
+            "Found {other} as \"include\" in {}, expected a list of paths",
+            path.display()
+        ),
+        None => vec![],
+    };
+
+    let mut config = Table::new();
+    including.push(canonical);
+    for include in includes {
+        let Value::String(include) = include else {
+            bail!("Found {include} in \"include\" of {}", path.display());
+        };
+        let include_path = path.parent().unwrap_or(Path::new(".")).join(&include);
+        let included = read_including(&include_path, including)
+            .with_context(|| format!("Could not include {include} in {}", path.display()))?;
+        merge(&mut config, included)?;
+    }
+    including.pop();
+
+    merge(&mut config, table)?;
+    Ok(config)
+}
+
+/// Merges `layer` over `config`. Tables are merged key by key, so a layer can change the colors
+/// of a few groups, add groups, or replace some keys of a group and keep the rest.
+/// `addPatterns` in a group adds to the patterns it has so far, instead of replacing them.
+pub fn merge(config: &mut Table, layer: Table) -> anyhow::Result<()> {
+    for (name, value) in layer {
+        let Value::Table(table) = value else {
+            config.insert(name, value);
+            continue;
+        };
+        let base = config
+            .entry(name.as_str())
+            .or_insert_with(|| Value::Table(Table::new()));
+        if !base.is_table() {
+            *base = Value::Table(Table::new());
+        }
+        let Value::Table(base) = base else {
+            unreachable!()
+        };
+
+        for (key, value) in table {
+            if key != "addPatterns" {
+                base.insert(key, value);
+                continue;
+            }
+            let Value::Array(added) = value else {
+                bail!("Found {value} as \'addPatterns\' of group {name}, expected an array");
+            };
+            match base.get_mut("patterns") {
+                Some(Value::Array(patterns)) => patterns.extend(added),
+                _ => {
+                    base.insert("patterns".to_string(), Value::Array(added));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The user config, if there is one
+pub fn user_config() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_home.join("denshi").join("config.toml")).filter(|path| path.is_file())
+}
+
+/// The `.denshi.toml` in `dir` or the closest directory above it, if there is one
+pub fn project_config(dir: &Path) -> Option<PathBuf> {
+    let dir = fs::canonicalize(dir)
+        .or_else(|_| std::path::absolute(dir))
+        .ok()?;
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax_matcher::test_items;
+
+    /// A new directory with `files` written into it
+    fn directory(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("denshi-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn configs_merge_over_their_includes() {
+        let dir = directory(
+            "include",
+            &[
+                (
+                    "base/base.toml",
+                    "[colors]\ndenshiKeyword = \"ctermfg=7\"\ndenshiComment = \"ctermfg=8\"\n\
+                     [denshiKeyword]\npatterns = [\"Keyword Locate\"]\npriority = 1\n\
+                     [denshiComment]\npatterns = [\"Comment Locate\"]\n",
+                ),
+                (
+                    "project/.denshi.toml",
+                    "include = [\"../base/base.toml\"]\n\
+                     [colors]\ndenshiKeyword = \"ctermfg=1\"\n\
+                     [denshiKeyword]\naddPatterns = [\"Symbol Locate\"]\n\
+                     [denshiComment]\npatterns = [\"Comment\"]\n",
+                ),
+                ("project/src/a.sv", ""),
+            ],
+        );
+
+        let path = project_config(&dir.join("project/src")).unwrap();
+        assert_eq!(
+            path,
+            fs::canonicalize(dir.join("project/.denshi.toml")).unwrap()
+        );
+
+        let config = read_config(&path).unwrap();
+        let expected = "[colors]\ndenshiComment = \"ctermfg=8\"\ndenshiKeyword = \"ctermfg=1\"\n\n\
+                        [denshiComment]\npatterns = [\"Comment\"]\n\n\
+                        [denshiKeyword]\npatterns = [\"Keyword Locate\", \"Symbol Locate\"]\n\
+                        priority = 1\n"
+            .parse::<Table>()
+            .unwrap();
+        assert_eq!(config, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// The groups the default config gives every token with `text`
+    fn groups_of<'c>(items: &[(&'c str, usize, &str)], text: &str) -> Vec<&'c str> {
+        items
+            .iter()
+            .filter(|(_, _, matched)| *matched == text)
+            .map(|(group, _, _)| *group)
+            .collect()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let config = load(None, None).unwrap();
+        assert!(crate::syntax_matcher::SyntaxMatcher::from_toml(&config).is_ok());
+    }
+
+    #[test]
+    fn default_config_highlights_data_type_keywords() {
+        let code = "module m;\n\
+                    logic [1:0] a;\n\
+                    always_comb begin\n\
+                    for (int j = 0; j < 2; j++) a[j] = 1;\n\
+                    end\n\
+                    endmodule\n";
+        let items = test_items(code, DEFAULT_CONFIG);
+
+        assert_eq!(groups_of(&items, "logic"), ["denshiKeyword"]);
+        assert_eq!(groups_of(&items, "int"), ["denshiKeyword"]);
+    }
+
+    #[test]
+    fn default_config_highlights_attributes() {
+        let code = "module m;\n\
+                    (* MARK_DEBUG = \"TRUE\", keep *) logic a, b;\n\
+                    always @(*) a = b;\n\
+                    endmodule\n";
+        let items = test_items(code, DEFAULT_CONFIG);
+        let attributes = items
+            .iter()
+            .filter(|(group, _, _)| group.starts_with("denshiAttribute"))
+            .map(|(group, _, text)| (*group, *text))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            attributes,
+            [
+                ("denshiAttribute", "(*"),
+                ("denshiAttributeName", "MARK_DEBUG"),
+                ("denshiAttributeValue", "\"TRUE\""),
+                ("denshiAttribute", ","),
+                ("denshiAttributeName", "keep"),
+                ("denshiAttribute", "*)"),
+            ]
+        );
+        //The wildcard sensitivity list parses, and isn't taken for an attribute
+        assert_eq!(groups_of(&items, "always"), ["denshiAlways"]);
+        assert!(groups_of(&items, "(").is_empty());
+    }
+
+    #[test]
+    fn configs_reject_include_cycles() {
+        let dir = directory(
+            "cycle",
+            &[
+                ("a.toml", "include = [\"b.toml\"]\n"),
+                ("b.toml", "include = [\"a.toml\"]\n"),
+            ],
+        );
+
+        let error = read_config(&dir.join("a.toml")).unwrap_err();
+        assert!(format!("{error:#}").contains("Config includes itself"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
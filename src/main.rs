@@ -1,31 +1,77 @@
+pub mod aliases;
+pub mod conditions;
+pub mod config;
 pub mod matcher;
+pub mod node_kind;
+pub mod parse;
+pub mod scopes;
+pub mod semantic_tokens;
+pub mod source_map;
 pub mod syntax_matcher;
 
+use crate::parse::{line_col, parse_code, parse_recovering, ParseFailure, ParseOptions};
+use crate::semantic_tokens::SemanticTokens;
+use crate::source_map::SourceMap;
 use crate::syntax_matcher::SyntaxMatcher;
-use crate::syntax_matcher::{SyntaxItem, SyntaxItemType};
+use crate::syntax_matcher::{
+    ColumnEncoding, MatchedSyntaxItem, Overlaps, SyntaxItem, SyntaxItemType,
+};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::rc::Rc;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::SystemTime;
 
-use sv_parser::parse_sv_str;
-use sv_parser::NodeEvent;
-use sv_parser::RefNode;
+use sv_parser::{NodeEvent, RefNode, SyntaxTree};
 
 use toml::Table;
 
+use anyhow::bail;
 use anyhow::Result;
 
-use ansi_term::Colour;
+use itertools::Itertools;
+use serde::Serialize;
 
 /// Parser for the associated nvim plugin for systemverilog syntax highlighting
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    code: String,
-    config: String,
+    /// The file to highlight, or `-` to read the code from stdin. Ignored by `serve`, and not
+    /// taken by `config`
+    code: Option<String>,
+    /// The base config, the built in default when not given. It is layered under the user config
+    /// in `$XDG_CONFIG_HOME/denshi/config.toml` and the `.denshi.toml` closest to the code, each
+    /// merged over the last
+    config: Option<PathBuf>,
+
+    /// Only read the base config, without looking for the user or project configs
+    #[arg(long)]
+    no_discover: bool,
+
+    /// Name to parse the code under, instead of the `code` path. Useful when reading from stdin
+    #[arg(long)]
+    filename: Option<String>,
+
+    /// Directory to search for `include files, after the directory of the code itself
+    #[arg(short = 'I', long = "incdir")]
+    incdirs: Vec<PathBuf>,
+
+    /// Define a macro for the preprocessor, as NAME or NAME=VALUE
+    #[arg(short = 'D', value_parser = parse_define)]
+    defines: Vec<(String, Option<String>)>,
+
+    /// What the columns count. Defaults to utf16 for semantic tokens, and utf8 everywhere else
+    #[arg(long, value_enum)]
+    column_encoding: Option<ColumnEncoding>,
+
+    /// What to do with code more than one group matched. Semantic tokens can't overlap, so they
+    /// always only get the winner
+    #[arg(long, value_enum, default_value_t)]
+    overlaps: Overlaps,
 
     #[command(subcommand)]
     command: Command,
@@ -33,45 +79,235 @@ struct Args {
 
 #[derive(Debug, Subcommand, PartialEq)]
 enum Command {
-    Parse,
+    Parse {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
     Debug,
     Colors,
-    List { line_num: i32 },
-    Find { regex: String },
+    List {
+        line_num: i32,
+    },
+    Find {
+        regex: String,
+    },
+    /// Report parse errors as `file:line:col: message` lines for the quickfix list, or
+    /// `file: message` for errors outside of the code, exiting with an error when there are any
+    Check {
+        /// Print `line col_start col_end message` lines instead, with the same 0 based columns
+        /// as `parse`, and zeros for errors outside of the code
+        #[arg(long)]
+        machine: bool,
+    },
+    /// Answer highlight requests on stdin, one per line. The `code` argument is ignored, every
+    /// request names its own source, and is highlighted with the config closest to it. A config
+    /// given without it would be taken for the code, so it has to come with both or neither.
+    /// Configs are read the first time a request needs them, and again once they are modified.
+    ///
+    /// Requests are either `<id> file <path>`, or `<id> text <length> [filename]` followed by
+    /// `<length>` bytes of source. Each is answered with `<id> ok <count> <failures>`, `<count>`
+    /// lines in the `parse` format and `<failures>` lines in the `check --machine` format for the
+    /// parts of the code that didn't parse, or with a single `<id> error <message>` line.
+    Serve,
+    /// Manage configs
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Subcommand, PartialEq)]
+enum ConfigCommand {
+    /// Write out the built in default config, to start a config of your own from
+    DumpDefault {
+        /// Where to write it, instead of stdout. Existing files are left alone
+        path: Option<PathBuf>,
+    },
 }
 
-fn main() -> Result<()> {
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// `group line col_start col_end matched` per item
+    Text,
+    /// An object with the array of `items`, and the `failures` of the parts that didn't parse
+    Json,
+    /// One item per line, then one line per failure
+    Jsonl,
+    /// An LSP `SemanticTokens` data array, with the legend of group names it refers to. Region
+    /// groups are left out.
+    SemanticTokens,
+}
+
+fn parse_define(define: &str) -> Result<(String, Option<String>)> {
+    if define.is_empty() {
+        bail!("Define can't be empty");
+    }
+    Ok(match define.split_once('=') {
+        Some((name, value)) => (name.to_string(), Some(value.to_string())),
+        None => (define.to_string(), None),
+    })
+}
+
+fn main() -> Result<ExitCode> {
     let args = Args::parse();
+    if let Command::Config { ref command } = args.command {
+        if let Some(code) = args.code {
+            bail!("config takes no code or config arguments, found {code}");
+        }
+        config_command(command)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let options = ParseOptions::new(&args.defines, &args.incdirs);
+    if args.command == Command::Serve {
+        if let (Some(code), None) = (&args.code, &args.config) {
+            bail!(
+                "serve ignores the code argument and takes the config after it, so {code} would \
+                 be ignored. Give a placeholder before it, like `- {code} serve`"
+            );
+        }
+        let columns = args.column_encoding.unwrap_or_default();
+        let base = args.config.as_deref();
+        serve(base, !args.no_discover, &options, columns, args.overlaps)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let Some(code_arg) = args.code.as_deref() else {
+        bail!("Expected the code to highlight");
+    };
+    let code_path = args.filename.as_deref().unwrap_or(code_arg);
+    let load_config = || {
+        let code_dir = (!args.no_discover).then(|| code_dir(code_path));
+        config::load(args.config.as_deref(), code_dir)
+    };
 
     match args.command {
-        Command::Parse | Command::Debug => {
-            let _result = parse_groups(&args.config, &args.code, args.command == Command::Debug)?;
+        Command::Parse { format } => {
+            let code = read_code(code_arg)?;
+            let columns = args.column_encoding;
+            parse_groups(
+                &load_config()?,
+                code,
+                code_path,
+                &options,
+                args.overlaps,
+                Some((format, columns)),
+            )?;
+        }
+        Command::Debug => {
+            let code = read_code(code_arg)?;
+            parse_groups(
+                &load_config()?,
+                code,
+                code_path,
+                &options,
+                args.overlaps,
+                None,
+            )?;
         }
-        Command::Find { regex } => {
-            find_regex(&args.code, &regex)?;
+        Command::Find { ref regex } => {
+            find_regex(read_code(code_arg)?, code_path, &options, regex)?;
         }
-        Command::Colors {} => {
-            let _result = print_colors(&args.config)?;
+        Command::Colors => {
+            print_colors(&load_config()?)?;
         }
         Command::List { line_num } => {
-            let _result = print_all_on_line(&args.code, line_num)?;
+            print_all_on_line(read_code(code_arg)?, code_path, &options, line_num)?;
         }
+        Command::Check { machine } => {
+            let columns = args.column_encoding.unwrap_or_default();
+            return check(read_code(code_arg)?, code_path, &options, machine, columns);
+        }
+        Command::Config { .. } | Command::Serve => unreachable!(),
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-fn print_all_on_line(code_path: &str, linenum: i32) -> Result<()> {
-    let code = preprocess(code_path)?;
+/// Group given to compiler directives, which are found without going through the matchers
+const COMPILER_DIRECTIVE_GROUP: &str = "denshiCompilerDirective";
+
+/// Group given to the token the parser failed on
+const PARSE_ERROR_GROUP: &str = "denshiParseError";
+
+/// Finds the compiler directives in `code`. Directives still in the tree, like `timescale, are
+/// taken from it. The ones the preprocessor consumed, like `ifdef, `include or macro usages, are
+/// whatever is left in the code that starts with a backtick.
+fn compiler_directives(
+    tree: &SyntaxTree,
+    code: &str,
+    source_map: &SourceMap,
+) -> Vec<SyntaxItem<'static>> {
+    let mut output = vec![];
+    let mut directive_depth = 0;
+    let mut whitespace_depth = 0;
+    let mut span: Option<(usize, usize)> = None;
+
+    for node_event in tree.into_iter().event() {
+        match node_event {
+            NodeEvent::Enter(RefNode::CompilerDirective(_))
+            | NodeEvent::Enter(RefNode::ResetallCompilerDirective(_)) => directive_depth += 1,
+            NodeEvent::Leave(RefNode::CompilerDirective(_))
+            | NodeEvent::Leave(RefNode::ResetallCompilerDirective(_)) => {
+                directive_depth -= 1;
+                if directive_depth == 0 {
+                    if let Some((col_start, col_end)) = span.take() {
+                        output.push(SyntaxItem {
+                            group: COMPILER_DIRECTIVE_GROUP,
+                            col_start,
+                            col_end,
+                            syntax_type: SyntaxItemType::Always,
+                        });
+                    }
+                }
+            }
+            NodeEvent::Enter(RefNode::WhiteSpace(_)) if directive_depth > 0 => {
+                whitespace_depth += 1
+            }
+            NodeEvent::Leave(RefNode::WhiteSpace(_)) if directive_depth > 0 => {
+                whitespace_depth -= 1
+            }
+            NodeEvent::Enter(RefNode::Locate(locate))
+                if directive_depth > 0 && whitespace_depth == 0 =>
+            {
+                if let Some(range) =
+                    source_map.original_range(locate.offset, locate.offset + locate.len)
+                {
+                    let (start, _) = span.unwrap_or((range.start, range.end));
+                    span = Some((start, range.end));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    for range in source_map.unmapped(code.len()) {
+        let mut line_start = range.start;
+        for line in code[range.clone()].split_inclusive('\n') {
+            let trimmed = line.trim();
+            if trimmed.starts_with('`') {
+                let col_start = line_start + line.find('`').unwrap();
+                output.push(SyntaxItem {
+                    group: COMPILER_DIRECTIVE_GROUP,
+                    col_start,
+                    col_end: col_start + trimmed.len(),
+                    syntax_type: SyntaxItemType::Always,
+                });
+            }
+            line_start += line.len();
+        }
+    }
+
+    output
+}
 
-    let (tree, _) = parse_sv_str(
-        &code,
-        PathBuf::from(code_path),
-        &HashMap::new(),
-        &Vec::<PathBuf>::new(),
-        false,
-        false,
-    )?;
+fn print_all_on_line(
+    code: String,
+    code_path: &str,
+    options: &ParseOptions,
+    linenum: i32,
+) -> Result<()> {
+    let tree = parse_code(&code, code_path, options)?;
+    let source_map = SourceMap::new(&tree, &code, Path::new(code_path));
 
     let mut breadcrumbs = vec![];
     for node_event in tree.into_iter().event() {
@@ -85,8 +321,12 @@ fn print_all_on_line(code_path: &str, linenum: i32) -> Result<()> {
         };
 
         if let NodeEvent::Enter(RefNode::Locate(locate)) = node_event {
-            if locate.line == linenum as u32 {
-                println!("{} {breadcrumbs:?}", locate.str(&code));
+            let Some(range) = source_map.original_range(locate.offset, locate.offset) else {
+                continue;
+            };
+            if code[..range.start].matches('\n').count() + 1 == linenum as usize {
+                let name = tree.get_str(locate).unwrap_or_default();
+                println!("{name} {breadcrumbs:?}");
             }
         }
     }
@@ -94,17 +334,14 @@ fn print_all_on_line(code_path: &str, linenum: i32) -> Result<()> {
     Ok(())
 }
 
-fn find_regex(code_path: &str, input_filter: &str) -> Result<()> {
-    let code = preprocess(code_path)?;
-
-    let (tree, _) = parse_sv_str(
-        &code,
-        PathBuf::from(code_path),
-        &HashMap::new(),
-        &Vec::<PathBuf>::new(),
-        false,
-        false,
-    )?;
+fn find_regex(
+    code: String,
+    code_path: &str,
+    options: &ParseOptions,
+    input_filter: &str,
+) -> Result<()> {
+    let tree = parse_code(&code, code_path, options)?;
+    let source_map = SourceMap::new(&tree, &code, Path::new(code_path));
 
     let mut breadcrumbs = vec![];
     for node_event in tree.into_iter().event() {
@@ -118,12 +355,15 @@ fn find_regex(code_path: &str, input_filter: &str) -> Result<()> {
         };
 
         if let NodeEvent::Enter(RefNode::Locate(locate)) = node_event {
-            let name = locate.str(&code);
+            let name = tree.get_str(locate).unwrap_or_default();
             if name == input_filter {
+                let Some(range) = source_map.original_range(locate.offset, locate.offset) else {
+                    continue;
+                };
                 println!(
                     "\nLine: {}\n{}",
                     code.lines()
-                        .nth((locate.line - 1).try_into().unwrap())
+                        .nth(code[..range.start].matches('\n').count())
                         .unwrap(),
                     breadcrumbs
                         .clone()
@@ -138,12 +378,29 @@ fn find_regex(code_path: &str, input_filter: &str) -> Result<()> {
     Ok(())
 }
 
-fn print_colors(toml_path: &str) -> Result<()> {
+fn config_command(command: &ConfigCommand) -> Result<()> {
+    match command {
+        ConfigCommand::DumpDefault { path: None } => print!("{}", config::DEFAULT_CONFIG),
+        ConfigCommand::DumpDefault { path: Some(path) } => {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(config::DEFAULT_CONFIG.as_bytes()))
+                .context(format!(
+                    "Could not write the default config to {}",
+                    path.display()
+                ))?;
+        }
+    }
+    Ok(())
+}
+
+fn print_colors(config: &Table) -> Result<()> {
     //TODO would be good to separate out some of this, so that we don't need to create an entire
     //SyntaxMatcher just to print the colors
 
-    let parsed_toml = &fs::read_to_string(toml_path)?.parse::<Table>()?;
-    let matcher = SyntaxMatcher::from_toml(parsed_toml)?;
+    let matcher = SyntaxMatcher::from_toml(config)?;
 
     for (color, string) in matcher.get_colors() {
         println!("{} {}", color, string);
@@ -152,123 +409,432 @@ fn print_colors(toml_path: &str) -> Result<()> {
     Ok(())
 }
 
-fn preprocess(code_path: &str) -> Result<Rc<String>> {
-    let mut code = fs::read_to_string(code_path)?;
+/// The directory the project config for the code at `code_path` is looked for from
+fn code_dir(code_path: &str) -> &Path {
+    Path::new(code_path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
 
-    let mut backtick_indices = vec![];
-    for (p, _) in code.char_indices().rev().filter(|(_, c)| c == &'`') {
-        backtick_indices.push(p);
+/// Reads the code to highlight, `-` reads it from stdin
+fn read_code(code_path: &str) -> Result<String> {
+    if code_path == "-" {
+        let mut code = String::new();
+        std::io::stdin()
+            .read_to_string(&mut code)
+            .context("Could not read the code from stdin")?;
+        Ok(code)
+    } else {
+        fs::read_to_string(code_path).with_context(|| format!("Could not read {code_path}"))
     }
+}
 
-    let mut compiler_directives = vec![];
-    for p in backtick_indices {
-        let line_end = code
-            .char_indices()
-            .skip(p)
-            .take_while(|(_, c)| c != &'\n')
-            .map(|(p, _)| p)
-            .last()
-            .unwrap_or(code.len() - 1);
-
-        code.replace_range(p..line_end + 1, "");
-        compiler_directives.push(SyntaxItem {
-            group: "denshiCompilerDirective",
-            col_start: p,
-            col_end: line_end + 1,
+/// Parses `code`, drives `matcher` over everything that parsed and resolves the groups. If some
+/// of the code didn't parse the failures are returned as well, and marked in the items when
+/// they are in `code` itself.
+fn highlight<'a: 'c, 'c>(
+    matcher: &mut SyntaxMatcher<'a>,
+    code: &'c str,
+    code_path: &str,
+    options: &ParseOptions,
+) -> (Vec<MatchedSyntaxItem<'c>>, Vec<ParseFailure>) {
+    let (trees, failures) = parse_recovering(code, code_path, options);
+
+    let mut items = vec![];
+    for tree in &trees {
+        let source_map = SourceMap::new(tree, code, Path::new(code_path));
+
+        for node_event in tree.into_iter().event() {
+            match node_event {
+                NodeEvent::Enter(ref node) => matcher.enter(node, tree),
+                NodeEvent::Leave(_) => matcher.leave(),
+            };
+        }
+
+        items.append(&mut matcher.compute_items(code, &source_map));
+        items.append(&mut compiler_directives(tree, code, &source_map));
+    }
+
+    for range in failures
+        .iter()
+        .filter_map(|failure| failure.token_range(code))
+    {
+        items.push(SyntaxItem {
+            group: PARSE_ERROR_GROUP,
+            col_start: range.start,
+            col_end: range.end,
             syntax_type: SyntaxItemType::Always,
         });
     }
 
-    //Vivado, for example, uses (*  ...     *)  logic param_name; as a way to declare parameters on
-    //nets. Treat these as comments. This is not ideal.
-    code = code.replace("(*", "/*");
-    code = code.replace("*)", "*/");
-
-    Ok(Rc::new(code))
+    //Every tree sees the directives of the whole file, so these repeat when recovering
+    let items = items.into_iter().unique().collect();
+    (matcher.resolve(items, code), failures)
 }
 
-fn parse_groups(toml_path: &str, code_path: &str, debug: bool) -> Result<()> {
-    let code = preprocess(code_path)?;
+fn format_item(item: &MatchedSyntaxItem) -> String {
+    format!(
+        "{} {} {} {} {}",
+        item.group, item.line, item.col_start, item.col_end, item.matched
+    )
+}
 
-    let result = parse_sv_str(
-        &code,
-        PathBuf::from(code_path),
-        &HashMap::new(),
-        &Vec::<PathBuf>::new(),
-        false,
-        false,
-    );
+/// A parse failure as the machine readable outputs give it, with the line and columns of the
+/// token it points at when it points into the code
+#[derive(Debug, Serialize)]
+struct FailureReport<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    col_start: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    col_end: Option<usize>,
+    message: &'a str,
+}
 
-    let parsed_toml = &fs::read_to_string(toml_path)?.parse::<Table>()?;
-    let mut matcher = SyntaxMatcher::from_toml(parsed_toml)?;
+impl<'a> FailureReport<'a> {
+    fn new(failure: &'a ParseFailure, code: &str, columns: ColumnEncoding) -> Self {
+        let range = failure.token_range(code);
+        let position = range.map(|range| {
+            let (line, col) = line_col(code, range.start);
+            let line_start = range.start - col;
+            (
+                line,
+                columns.units(&code[line_start..range.start]),
+                columns.units(&code[line_start..range.end]),
+            )
+        });
+        Self {
+            line: position.map(|(line, _, _)| line),
+            col_start: position.map(|(_, col_start, _)| col_start),
+            col_end: position.map(|(_, _, col_end)| col_end),
+            message: &failure.message,
+        }
+    }
 
-    let mut breadcrumbs = vec![];
+    /// `line col_start col_end message`, with zeros when there is no position
+    fn format(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.line.unwrap_or_default(),
+            self.col_start.unwrap_or_default(),
+            self.col_end.unwrap_or_default(),
+            self.message.replace('\n', " ")
+        )
+    }
+}
 
-    let (tree, _) = result?;
+/// `failure` as `file:line:col: message`, or `file: message` when it has no position in the code
+fn describe_failure(failure: &ParseFailure, code: &str, code_path: &str) -> String {
+    match failure.token_range(code) {
+        Some(range) => {
+            let (line, col) = line_col(code, range.start);
+            format!("{code_path}:{line}:{}: {}", col + 1, failure.message)
+        }
+        None => format!("{code_path}: {}", failure.message),
+    }
+}
 
-    for node_event in tree.into_iter().event() {
-        match node_event {
-            NodeEvent::Enter(ref node) => {
-                breadcrumbs.push(node.to_string());
-                matcher.enter(node);
-            }
-            NodeEvent::Leave(ref node) => {
-                breadcrumbs.pop();
-                matcher.leave(node);
-            }
-        };
+/// Prints the highlighted groups in `format` with columns in the given encoding, or colors the
+/// code with them when it is `None`
+fn parse_groups(
+    config: &Table,
+    code: String,
+    code_path: &str,
+    options: &ParseOptions,
+    overlaps: Overlaps,
+    format: Option<(OutputFormat, Option<ColumnEncoding>)>,
+) -> Result<()> {
+    let mut matcher = SyntaxMatcher::from_toml(config)?;
+    if !matches!(format, Some((OutputFormat::SemanticTokens, _))) {
+        matcher.set_overlaps(overlaps);
     }
 
     let group_colors = matcher.get_colors_as_ansi()?;
 
-    let mut output_groups = matcher.compute(&code);
-    if !debug {
+    let (mut output_groups, failures) = highlight(&mut matcher, &code, code_path, options);
+    for failure in &failures {
+        eprintln!("{}", describe_failure(failure, &code, code_path));
+    }
+    if let Some((format, columns)) = format {
+        if format != OutputFormat::SemanticTokens {
+            for item in &mut output_groups {
+                item.encode_columns(&code, columns.unwrap_or_default());
+            }
+        }
+
         //Print the groups as input to the vim plugin
-        print!(
-            "{}",
-            output_groups
-                .iter()
-                .map(|item| {
-                    format!(
-                        "{} {} {} {} {}",
-                        item.group, item.line, item.col_start, item.col_end, item.matched
-                    )
-                })
-                .collect::<Vec<_>>()
-                .join("\n")
-        );
+        match format {
+            OutputFormat::Text => print!(
+                "{}",
+                output_groups
+                    .iter()
+                    .map(format_item)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+            OutputFormat::Json => {
+                let failures = failures
+                    .iter()
+                    .map(|failure| FailureReport::new(failure, &code, columns.unwrap_or_default()))
+                    .collect::<Vec<_>>();
+                #[derive(Serialize)]
+                struct Output<'a, 'c> {
+                    items: &'a [MatchedSyntaxItem<'c>],
+                    failures: Vec<FailureReport<'a>>,
+                }
+                let output = Output {
+                    items: &output_groups,
+                    failures,
+                };
+                println!("{}", serde_json::to_string(&output)?);
+            }
+            OutputFormat::Jsonl => {
+                for item in &output_groups {
+                    println!("{}", serde_json::to_string(item)?);
+                }
+                for failure in &failures {
+                    let failure = FailureReport::new(failure, &code, columns.unwrap_or_default());
+                    println!("{}", serde_json::to_string(&failure)?);
+                }
+            }
+            OutputFormat::SemanticTokens => {
+                //Regions would overlap the tokens in them, which semantic tokens can't
+                let mut token_types = matcher
+                    .get_groups()
+                    .iter()
+                    .copied()
+                    .filter(|group| !matcher.is_region(group))
+                    .collect::<Vec<_>>();
+                token_types.extend([COMPILER_DIRECTIVE_GROUP, PARSE_ERROR_GROUP]);
+                let columns = columns.unwrap_or(ColumnEncoding::Utf16);
+                let tokens = SemanticTokens::new(&output_groups, &code, token_types, columns);
+                println!("{}", serde_json::to_string(&tokens)?);
+            }
+        }
     } else {
         for (group, c) in &group_colors {
-            println!("Group: {}{}{}", c, group, "\x1b[0m");
+            println!("Group: {}{}\x1b[0m", c, group);
         }
 
-        let mut lines = code
-            .split("\n")
-            .map(|x| x.to_string())
-            .collect::<Vec<String>>();
-        output_groups.sort_by(|a, b| usize::cmp(&b.col_start, &a.col_start));
-        output_groups.sort_by(|a, b| usize::cmp(&b.line, &a.line));
         dbg!(&output_groups);
-        for item in output_groups {
-            lines
-                .get_mut(item.line - 1)
-                .context(format!("Could not get line for item {:?}", item))?
-                .replace_range(
-                    item.col_start..item.col_end,
-                    format!(
-                        "{}{}{}",
-                        group_colors
-                            .get(item.group)
-                            .context(format!("Could not find color for group {}", item.group))?,
-                        item.matched,
-                        "\x1b[0m"
-                    )
-                    .as_ref(),
+        println!("{}", color_code(&code, &output_groups, &group_colors)?);
+    }
+    Ok(())
+}
+
+/// `code` with every item in `items` colored with the ANSI codes of its group. Each line is cut
+/// where any item starts or ends, and every piece gets the codes of all items covering it in their
+/// order, so regions stay behind the tokens in them and the last of stacked items wins.
+fn color_code(
+    code: &str,
+    items: &[MatchedSyntaxItem],
+    group_colors: &HashMap<String, String>,
+) -> Result<String> {
+    let mut lines = code.split('\n').map(str::to_string).collect::<Vec<_>>();
+    for (line, items) in &items.iter().into_group_map_by(|item| item.line) {
+        let text = lines
+            .get_mut(line - 1)
+            .context(format!("Could not get line for item {:?}", items[0]))?;
+        let cuts = items
+            .iter()
+            .flat_map(|item| [item.col_start, item.col_end])
+            .chain([0, text.len()])
+            .sorted()
+            .dedup()
+            .collect::<Vec<_>>();
+        let mut colored = String::new();
+        for (start, end) in cuts.into_iter().tuple_windows() {
+            let mut covering = items
+                .iter()
+                .filter(|item| item.col_start <= start && end <= item.col_end)
+                .peekable();
+            let reset = covering.peek().is_some();
+            for item in covering {
+                colored.push_str(
+                    group_colors
+                        .get(item.group)
+                        .context(format!("Could not find color for group {}", item.group))?,
                 );
+            }
+            colored.push_str(&text[start..end]);
+            if reset {
+                colored.push_str("\x1b[0m");
+            }
         }
-        println!("{}", lines.join("\n"));
+        *text = colored;
+    }
+    Ok(lines.join("\n"))
+}
+
+fn check(
+    code: String,
+    code_path: &str,
+    options: &ParseOptions,
+    machine: bool,
+    columns: ColumnEncoding,
+) -> Result<ExitCode> {
+    let (_, failures) = parse_recovering(&code, code_path, options);
+
+    for failure in &failures {
+        if machine {
+            println!("{}", FailureReport::new(failure, &code, columns).format());
+        } else {
+            println!("{}", describe_failure(failure, &code, code_path));
+        }
+    }
+
+    Ok(if failures.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// A single request read by `serve`
+struct ServeRequest {
+    code_path: String,
+    code: String,
+}
+
+fn read_request(input: &mut impl BufRead, header: &str) -> Result<ServeRequest> {
+    let mut fields = header.splitn(3, ' ').skip(1);
+    let kind = fields
+        .next()
+        .context("Expected a request kind after the id")?;
+    let rest = fields
+        .next()
+        .context("Expected arguments after the request kind")?;
+
+    match kind {
+        "file" => Ok(ServeRequest {
+            code_path: rest.to_string(),
+            code: fs::read_to_string(rest).with_context(|| format!("Could not read {rest}"))?,
+        }),
+        "text" => {
+            let (len, code_path) = rest.split_once(' ').unwrap_or((rest, "<buffer>"));
+            let mut buf = vec![0; len.parse::<usize>()?];
+            input.read_exact(&mut buf)?;
+            Ok(ServeRequest {
+                code_path: code_path.to_string(),
+                code: String::from_utf8(buf)?,
+            })
+        }
+        other => bail!("Unknown request kind {other}"),
+    }
+}
+
+/// A matcher kept by `serve`, with the config files it was built from and when they were modified
+struct ServedMatcher {
+    matcher: SyntaxMatcher<'static>,
+    stamp: Vec<(Option<SystemTime>, PathBuf)>,
+}
+
+/// Answers the requests on stdin, with the config at `base` layered under the user and project
+/// configs when `discover` is set
+fn serve(
+    base: Option<&Path>,
+    discover: bool,
+    options: &ParseOptions,
+    columns: ColumnEncoding,
+    overlaps: Overlaps,
+) -> Result<()> {
+    //A broken base config would break every request, so it stops serve before the first one
+    SyntaxMatcher::from_toml(&config::load(base, None)?)?;
+    //The matchers built so far, by the project config they were built with. They are rebuilt when
+    //one of the config files they were read from changes, but not when only an included one does
+    let mut matchers: HashMap<Option<PathBuf>, ServedMatcher> = HashMap::new();
+
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout().lock();
+
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if stdin.read_line(&mut header)? == 0 {
+            return Ok(());
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            continue;
+        }
+
+        let id = header.split(' ').next().unwrap_or_default();
+        let result = read_request(&mut stdin, header).and_then(|request| {
+            let code_dir = discover.then(|| code_dir(&request.code_path));
+            let project = code_dir.and_then(config::project_config);
+            let user = discover.then(config::user_config).flatten();
+            let stamp = [base.map(Path::to_path_buf), user, project.clone()]
+                .into_iter()
+                .flatten()
+                .map(|path| (fs::metadata(&path).and_then(|m| m.modified()).ok(), path))
+                .collect::<Vec<_>>();
+            let matcher = match matchers.entry(project) {
+                Entry::Occupied(entry) if entry.get().stamp == stamp => entry.into_mut(),
+                entry => {
+                    //The matcher borrows its config for the rest of the session. Configs only
+                    //change by hand, so the few replaced ones are leaked rather than tracked
+                    let config = Box::leak(Box::new(config::load(base, code_dir)?));
+                    let mut matcher = SyntaxMatcher::from_toml(config)?;
+                    matcher.set_overlaps(overlaps);
+                    let served = ServedMatcher { matcher, stamp };
+                    match entry {
+                        Entry::Occupied(mut entry) => {
+                            entry.insert(served);
+                            entry.into_mut()
+                        }
+                        Entry::Vacant(entry) => entry.insert(served),
+                    }
+                }
+            };
+            let matcher = &mut matcher.matcher;
+
+            let (mut items, failures) =
+                highlight(matcher, &request.code, &request.code_path, options);
+            for item in &mut items {
+                item.encode_columns(&request.code, columns);
+            }
+            let failures = failures
+                .iter()
+                .map(|failure| FailureReport::new(failure, &request.code, columns).format())
+                .collect::<Vec<_>>();
+            Ok((items.iter().map(format_item).collect::<Vec<_>>(), failures))
+        });
+
+        match result {
+            Ok((lines, failures)) => {
+                writeln!(stdout, "{id} ok {} {}", lines.len(), failures.len())?;
+                for line in lines.iter().chain(&failures) {
+                    writeln!(stdout, "{line}")?;
+                }
+            }
+            Err(e) => writeln!(stdout, "{id} error {}", format!("{e:#}").replace('\n', " "))?,
+        }
+        stdout.flush()?;
     }
-    Ok(())
 }
 
 use anyhow::Context;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_colors_keep_regions_behind_their_tokens() {
+        let code = "module m;\nalways_comb a = b;\nendmodule\n";
+        let config = config::load(None, None).unwrap();
+        let mut matcher = SyntaxMatcher::from_toml(&config).unwrap();
+        let colors = matcher.get_colors_as_ansi().unwrap();
+        let options = ParseOptions::new(&[], &[]);
+        let (items, _) = highlight(&mut matcher, code, "test.sv", &options);
+        let colored = color_code(code, &items, &colors).unwrap();
+
+        let region = &colors["denshiAlwaysRegion"];
+        let always = &colors["denshiAlways"];
+        assert!(colored.contains(&format!("{region}{always}always_comb\x1b[0m{region} ")));
+        let escapes = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        assert_eq!(escapes.replace_all(&colored, ""), code);
+    }
+}
@@ -1,9 +1,246 @@
+use crate::node_kind::NodeKind;
+use anyhow::bail;
+use itertools::Itertools;
+use regex::Regex;
 use sv_parser::RefNode;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MatchPattern<'a> {
     Matches(&'a str),
     NotMatches(&'a str),
+    /// `(A|B)`, a node of any of the kinds
+    OneOf(Vec<&'a str>),
+    /// `?`, a single node of any kind
+    Any,
+    /// `>`, the next step has to be a direct child of the previous one
+    DirectChild,
+    /// `step{min,max}`, a run of `min` to `max` nodes each directly below the previous one.
+    /// `*` is `?{0,}`
+    Repeat(Box<MatchPattern<'a>>, usize, Option<usize>),
+    /// `step[/regex/]` or `step[="text"]`, a node whose text also has to match. Only `Locate`s
+    /// have any text
+    WithText(Box<MatchPattern<'a>>, TextPredicate),
+}
+
+#[derive(Clone, Debug)]
+pub enum TextPredicate {
+    Equals(String),
+    Regex(Regex),
+}
+
+impl TextPredicate {
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            TextPredicate::Equals(expected) => text == expected,
+            TextPredicate::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+impl PartialEq for TextPredicate {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (TextPredicate::Equals(a), TextPredicate::Equals(b)) => a == b,
+            (TextPredicate::Regex(a), TextPredicate::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+/// Parses a pattern from the config, a space separated list of steps. Node names in steps can be
+/// globs, like `*Identifier`
+pub fn parse_pattern(pattern: &str) -> anyhow::Result<Vec<MatchPattern<'_>>> {
+    let mut output = vec![];
+    for step in split_steps(pattern)? {
+        let (name, repeat) = match step
+            .strip_suffix('}')
+            .and_then(|step| step.rsplit_once('{'))
+        {
+            Some((name, bounds)) => (name, Some(parse_bounds(bounds)?)),
+            None => (step, None),
+        };
+        let (name, predicate) = match name.split_once('[') {
+            Some((name, rest)) if predicate_len(rest)? == rest.len() => {
+                (name, Some(parse_predicate(&rest[..rest.len() - 1])?))
+            }
+            Some(_) => bail!("Unexpected text after the text predicate in \"{step}\""),
+            None => (name, None),
+        };
+        if predicate.is_some() && (matches!(name, "*" | ">" | "") || name.starts_with('^')) {
+            bail!("\"{step}\" can't have a text predicate");
+        }
+
+        let step_pattern = match name {
+            "" | "^" => bail!("Empty step \"{step}\""),
+            "*" => MatchPattern::Repeat(Box::new(MatchPattern::Any), 0, None),
+            ">" => MatchPattern::DirectChild,
+            "?" => MatchPattern::Any,
+            name => match name.strip_prefix('^') {
+                //`^(A|B)` forbids both, which is the same as `^A ^B`
+                Some(name) => {
+                    if repeat.is_some() {
+                        bail!("\"{step}\" can't be repeated");
+                    }
+                    let names = parse_alternatives(name)?;
+                    output.extend(names.into_iter().map(MatchPattern::NotMatches));
+                    continue;
+                }
+                None => match parse_alternatives(name)?.as_slice() {
+                    [name] => MatchPattern::Matches(name),
+                    names => MatchPattern::OneOf(names.to_vec()),
+                },
+            },
+        };
+
+        let step_pattern = match predicate {
+            Some(predicate) => MatchPattern::WithText(Box::new(step_pattern), predicate),
+            None => step_pattern,
+        };
+
+        output.push(match (step_pattern, repeat) {
+            (step_pattern, None) => step_pattern,
+            (
+                MatchPattern::NotMatches(_) | MatchPattern::DirectChild | MatchPattern::Repeat(..),
+                Some(_),
+            ) => {
+                bail!("\"{step}\" can't be repeated")
+            }
+            (step_pattern, Some((min, max))) => {
+                MatchPattern::Repeat(Box::new(step_pattern), min, max)
+            }
+        });
+    }
+    Ok(output)
+}
+
+/// Splits a pattern into its steps at whitespace, except inside `[...]` text predicates
+pub fn split_steps(pattern: &str) -> anyhow::Result<Vec<&str>> {
+    let mut steps = vec![];
+    let mut start = None;
+    let mut index = 0;
+    while let Some(c) = pattern[index..].chars().next() {
+        if c.is_whitespace() {
+            if let Some(start) = start.take() {
+                steps.push(&pattern[start..index]);
+            }
+        } else {
+            start.get_or_insert(index);
+            if c == '[' {
+                index += 1 + predicate_len(&pattern[index + 1..])?;
+                continue;
+            }
+        }
+        index += c.len_utf8();
+    }
+    if let Some(start) = start {
+        steps.push(&pattern[start..]);
+    }
+    Ok(steps)
+}
+
+/// The length of the text predicate `text` starts with, `/regex/]` or `="text"]`, up to and
+/// including the closing `]`
+fn predicate_len(text: &str) -> anyhow::Result<usize> {
+    let (quote, body_start) = match (text.chars().next(), text.chars().nth(1)) {
+        (Some('/'), _) => ('/', 1),
+        (Some('='), Some(quote @ ('"' | '\''))) => (quote, 2),
+        _ => bail!("Expected /regex/ or =\"text\" after the [ in \"[{text}\""),
+    };
+
+    let mut escaped = false;
+    for (index, c) in text[body_start..].char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            let end = body_start + index + c.len_utf8();
+            if !text[end..].starts_with(']') {
+                bail!("Expected ] to close the text predicate in \"[{text}\"");
+            }
+            return Ok(end + 1);
+        }
+    }
+    bail!("Unterminated text predicate \"[{text}\"")
+}
+
+/// Parses the inside of a text predicate, without its brackets
+fn parse_predicate(predicate: &str) -> anyhow::Result<TextPredicate> {
+    if let Some(regex) = predicate.strip_prefix('/') {
+        let regex = regex[..regex.len() - 1].replace("\\/", "/");
+        return Ok(TextPredicate::Regex(Regex::new(&regex)?));
+    }
+
+    let quoted = &predicate[1..];
+    let mut text = String::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+    Ok(TextPredicate::Equals(text))
+}
+
+/// Whether the node kind `name` matches `pattern`, where a `*` in the pattern matches any run of
+/// characters
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let Some(last) = parts.next_back() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Parses `(A|B|...)` into its alternatives, or a plain name into just itself
+fn parse_alternatives(name: &str) -> anyhow::Result<Vec<&str>> {
+    let names = match name
+        .strip_prefix('(')
+        .and_then(|name| name.strip_suffix(')'))
+    {
+        Some(inner) => inner.split('|').collect::<Vec<_>>(),
+        None => vec![name],
+    };
+    for name in &names {
+        if name.is_empty() || name.contains(['(', ')', '|']) {
+            bail!(
+                "Invalid node name \"{name}\" in \"{}\", write alternatives as (A|B)",
+                names.join("|")
+            );
+        }
+    }
+    Ok(names)
+}
+
+/// Parses the inside of `{m,n}`, `{m,}`, `{,n}` or `{m}`
+fn parse_bounds(bounds: &str) -> anyhow::Result<(usize, Option<usize>)> {
+    let parse = |bound: &str| -> anyhow::Result<usize> {
+        bound
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid repetition bound \"{bound}\""))
+    };
+    let (min, max) = match bounds.split_once(',') {
+        Some((min, "")) => (parse(min)?, None),
+        Some(("", max)) => (0, Some(parse(max)?)),
+        Some((min, max)) => (parse(min)?, Some(parse(max)?)),
+        None => (parse(bounds)?, Some(parse(bounds)?)),
+    };
+    if max.is_some_and(|max| max < min) {
+        bail!("Repetition {{{bounds}}} has its maximum below its minimum");
+    }
+    Ok((min, max))
 }
 
 pub trait TryIntoLocate {
@@ -20,202 +257,544 @@ impl TryIntoLocate for RefNode<'_> {
     }
 }
 
-impl TryIntoLocate for &str {
-    fn try_into_locate(&self) -> Option<&sv_parser::Locate> {
-        None
-    }
-}
-
-pub struct BreadcrumbsMatcher<'a> {
-    pattern: Vec<MatchPattern<'a>>,
-    current_match: usize,
-    current_notmatch: Vec<usize>,
-    callback: Box<dyn Fn(&sv_parser::Locate) + 'a>,
+/// A set of node kinds
+#[derive(Debug, Clone)]
+struct KindSet {
+    contained: Vec<bool>,
+    kinds: Vec<NodeKind>,
 }
 
-impl<'a> BreadcrumbsMatcher<'a> {
-    pub fn new(
-        nodes: Vec<MatchPattern<'a>>,
-        callback: Box<dyn Fn(&sv_parser::Locate) + 'a>,
-    ) -> Self {
+impl KindSet {
+    fn new() -> Self {
         Self {
-            pattern: nodes.to_vec().clone(),
-            current_match: 0,
-            current_notmatch: vec![],
-            callback,
+            contained: vec![false; NodeKind::ALL.len()],
+            kinds: vec![],
         }
     }
-    pub fn enter<T>(&mut self, node: &T)
-    where
-        T: TryIntoLocate + ToString,
-    {
-        #[cfg(test)]
-        println!(
-            "ENTER: {:?}, {}, {:?}, {}",
-            self.pattern,
-            self.current_match,
-            self.current_notmatch,
-            node.to_string()
-        );
 
-        let mut local_match_counter = self.current_match;
-        let node_tostring = node.to_string();
-
-        while let Some(MatchPattern::NotMatches(patt)) = self.pattern.get(local_match_counter) {
-            if *patt == node_tostring {
-                self.current_notmatch.push(local_match_counter);
-                self.current_match = local_match_counter + 1;
+    /// Adds every kind matching the glob `name`, which has to match at least one
+    fn insert_matching(&mut self, name: &str) -> anyhow::Result<()> {
+        let kinds = NodeKind::matching(name);
+        if kinds.is_empty() {
+            match NodeKind::suggest(name).filter(|_| !name.contains('*')) {
+                Some(suggestion) => {
+                    bail!("Unknown node kind \"{name}\", did you mean \"{suggestion}\"?")
+                }
+                None => bail!("\"{name}\" doesn't match any node kind"),
+            }
+        }
+        for kind in kinds {
+            if !self.contains(kind) {
+                self.contained[kind as usize] = true;
+                self.kinds.push(kind);
             }
-            local_match_counter += 1;
         }
+        Ok(())
+    }
 
-        #[cfg(test)]
-        println!(
-            "LOOP : {:?}, {}, {:?}",
-            self.pattern.get(local_match_counter),
-            local_match_counter,
-            self.current_notmatch
-        );
+    fn contains(&self, kind: NodeKind) -> bool {
+        self.contained[kind as usize]
+    }
+}
 
-        match self.pattern.get(local_match_counter) {
-            None => {
-                if let Some(locate) = node.try_into_locate() {
-                    (self.callback)(locate);
+/// A step of a compiled pattern, consuming a single node
+#[derive(Debug)]
+struct Step {
+    //`None` matches any node
+    kinds: Option<KindSet>,
+    text: Option<TextPredicate>,
+    optional: bool,
+    //Whether the step can consume another node directly below the one it consumed
+    repeats: bool,
+}
+
+/// The nodes between two steps
+#[derive(Debug, Default)]
+struct Gap {
+    forbidden: Option<KindSet>,
+    //Whether the step after the gap has to directly follow the one before it
+    direct: bool,
+}
+
+#[derive(Debug)]
+struct Pattern {
+    steps: Vec<Step>,
+    //One more than `steps`, the gap before each step and the one after the last
+    gaps: Vec<Gap>,
+    //The id of the free state at the first position, the ones at the others follow it
+    first_id: usize,
+}
+
+/// How far along its pattern a node is. Gaps `since..=position` are the ones the nodes since the
+/// last consumed step are in, more than one when optional steps were skipped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    pattern: usize,
+    position: usize,
+    since: usize,
+    //Just consumed a repeating step, so only another repetition of it can follow
+    looped: bool,
+}
+
+impl Pattern {
+    fn new(nodes: Vec<MatchPattern>) -> anyhow::Result<Self> {
+        let mut steps = vec![];
+        let mut gaps = vec![Gap::default()];
+
+        for node in nodes {
+            let (node, min, max) = match node {
+                MatchPattern::NotMatches(name) => {
+                    let gap = gaps.last_mut().unwrap();
+                    gap.forbidden
+                        .get_or_insert_with(KindSet::new)
+                        .insert_matching(name)?;
+                    continue;
                 }
-            }
-            Some(MatchPattern::Matches(patt)) => {
-                if *patt == node_tostring {
-                    self.current_match = local_match_counter + 1;
-                    #[cfg(test)]
-                    println!(
-                        "Matched? {} == {} and {:?}",
-                        self.current_match,
-                        self.pattern.len(),
-                        self.current_notmatch
-                    );
-                    if self.matches() {
-                        if let Some(locate) = node.try_into_locate() {
-                            (self.callback)(locate);
-                        }
-                    }
+                MatchPattern::DirectChild => {
+                    gaps.last_mut().unwrap().direct = true;
+                    continue;
+                }
+                MatchPattern::Repeat(node, min, max) => (*node, min, max),
+                node => (node, 1, Some(1)),
+            };
+            let (node, text) = match node {
+                MatchPattern::WithText(node, text) => (*node, Some(text)),
+                node => (node, None),
+            };
+            let kinds = match node {
+                MatchPattern::Matches(name) => vec![name],
+                MatchPattern::OneOf(names) => names,
+                MatchPattern::Any => vec![],
+                other => unreachable!("{other:?} can't be a single step"),
+            };
+            let kinds = if kinds.is_empty() {
+                None
+            } else {
+                let mut set = KindSet::new();
+                for name in kinds {
+                    set.insert_matching(name)?;
                 }
+                Some(set)
+            };
+
+            //A run of `min` required steps, followed by up to `max - min` optional ones or a
+            //single optional one that repeats
+            let optional_count = max.map_or(1, |max| max - min);
+            for index in 0..min + optional_count {
+                if index > 0 {
+                    gaps.push(Gap {
+                        forbidden: None,
+                        direct: true,
+                    });
+                }
+                steps.push(Step {
+                    kinds: kinds.clone(),
+                    text: text.clone(),
+                    optional: index >= min,
+                    repeats: max.is_none() && index == min,
+                });
+            }
+            if min + optional_count > 0 {
+                gaps.push(Gap::default());
             }
-            _ => unreachable!(),
         }
-    }
 
-    pub fn leave<T>(&mut self, node: &T)
-    where
-        T: ToString,
-    {
-        #[cfg(test)]
-        println!(
-            "LEAVE: {:?}, {}, {:?}, {}",
-            self.pattern,
-            self.current_match,
-            self.current_notmatch,
-            node.to_string()
-        );
+        Ok(Self {
+            steps,
+            gaps,
+            first_id: 0,
+        })
+    }
 
-        if self.current_match == 0 {
-            assert!(
-                self.current_notmatch.len() == 0,
-                "Notmatch needs to be empty at level 0"
+    /// Adds `state` to the states of the node on top of `frames`, along with the states reached
+    /// by skipping optional steps
+    fn add_state(&self, free: &mut FreeStates, frames: &mut Frames, state: State) {
+        if self.is_free(&state) {
+            //Free states at the same position behave the same, so one of them is enough. The
+            //states skipping optional steps from it can still be new, their gaps start here.
+            let id = self.first_id + state.position;
+            if !free.active[id] {
+                free.activate(self, id);
+                frames.activated.push(id);
+            }
+        } else {
+            if frames.top().contains(&state) {
+                return;
+            }
+            frames.restricted.push(state);
+        }
+        if !state.looped
+            && self
+                .steps
+                .get(state.position)
+                .is_some_and(|step| step.optional)
+        {
+            self.add_state(
+                free,
+                frames,
+                State {
+                    position: state.position + 1,
+                    ..state
+                },
             );
-            return;
-        }
-
-        let node_tostring = node.to_string();
-
-        match self.pattern.get(self.current_match - 1) {
-            None => return,
-            Some(MatchPattern::Matches(patt)) => {
-                if *patt == node_tostring {
-                    self.current_match -= 1;
-                    #[cfg(test)]
-                    println!(
-                        "Leave Not Matched? {} == {} and {:?}",
-                        self.current_match,
-                        self.pattern.len(),
-                        self.current_notmatch
-                    );
+        }
+    }
+
+    /// Whether a node of `kind` can be in the gap `state` is in
+    fn gap_allows(&self, state: &State, kind: NodeKind) -> bool {
+        let gaps = &self.gaps[state.since..=state.position];
+        !state.looped
+            && gaps.iter().any(|gap| !gap.direct)
+            && gaps.iter().all(|gap| {
+                !gap.forbidden
+                    .as_ref()
+                    .is_some_and(|forbidden| forbidden.contains(kind))
+            })
+    }
+
+    /// Whether the gap `state` is in allows nodes of any kind
+    fn is_free(&self, state: &State) -> bool {
+        let gaps = &self.gaps[state.since..=state.position];
+        !state.looped
+            && gaps.iter().any(|gap| !gap.direct)
+            && gaps.iter().all(|gap| gap.forbidden.is_none())
+    }
+
+    fn accepts(&self, state: &State) -> bool {
+        !state.looped && state.position == self.steps.len()
+    }
+}
+
+/// The states added on entering each node of the path, those of a node after the ones of the
+/// node above it
+#[derive(Debug, Default)]
+struct Frames {
+    //States whose gap doesn't allow every node, checked against each node below
+    restricted: Vec<State>,
+    //Ids of the free states that became active
+    activated: Vec<usize>,
+    //Where the states of each node start in `restricted` and `activated`
+    starts: Vec<(usize, usize)>,
+}
+
+impl Frames {
+    fn push(&mut self) {
+        self.starts
+            .push((self.restricted.len(), self.activated.len()));
+    }
+
+    /// The restricted states of the current node
+    fn top(&self) -> &[State] {
+        &self.restricted[self.top_start()..]
+    }
+
+    fn top_start(&self) -> usize {
+        self.starts.last().map_or(0, |(restricted, _)| *restricted)
+    }
+}
+
+/// States in a gap that allows any node stay as they are for every node below the one they were
+/// reached on, so instead of being carried from node to node they stay active until that node is
+/// left. They are indexed by the kinds their next step consumes, so entering a node only looks
+/// at the ones it can move along.
+#[derive(Debug, Default)]
+struct FreeStates {
+    //The pattern and position of every id
+    positions: Vec<(usize, usize)>,
+    active: Vec<bool>,
+    //The active ids whose next step consumes each kind, by `NodeKind`, and any kind
+    waiting: Vec<Vec<usize>>,
+    waiting_any: Vec<usize>,
+    //The active ids at the end of their pattern
+    accepting: Vec<usize>,
+}
+
+impl FreeStates {
+    fn new(patterns: &[Pattern]) -> Self {
+        let mut positions = vec![];
+        for (index, pattern) in patterns.iter().enumerate() {
+            positions.extend((0..=pattern.steps.len()).map(|position| (index, position)));
+        }
+        Self {
+            active: vec![false; positions.len()],
+            positions,
+            waiting: vec![vec![]; NodeKind::ALL.len()],
+            waiting_any: vec![],
+            accepting: vec![],
+        }
+    }
+
+    fn activate(&mut self, pattern: &Pattern, id: usize) {
+        self.active[id] = true;
+        match pattern.steps.get(self.positions[id].1) {
+            None => self.accepting.push(id),
+            Some(Step { kinds: None, .. }) => self.waiting_any.push(id),
+            Some(Step {
+                kinds: Some(kinds), ..
+            }) => {
+                for kind in &kinds.kinds {
+                    self.waiting[*kind as usize].push(id);
                 }
             }
-            Some(MatchPattern::NotMatches(_)) => (),
         }
+    }
+
+    /// Undoes `activate`, which has to be the last one still in effect
+    fn deactivate(&mut self, pattern: &Pattern, id: usize) {
+        self.active[id] = false;
+        let last = match pattern.steps.get(self.positions[id].1) {
+            None => self.accepting.pop(),
+            Some(Step { kinds: None, .. }) => self.waiting_any.pop(),
+            Some(Step {
+                kinds: Some(kinds), ..
+            }) => kinds
+                .kinds
+                .iter()
+                .map(|kind| self.waiting[*kind as usize].pop())
+                .last()
+                .flatten(),
+        };
+        debug_assert_eq!(last, Some(id));
+    }
+}
+
+/// Matches patterns against the path from the root to the current node. Every step consumes a
+/// node on the path, in order, and the nodes between two consumed ones are the gap between those
+/// steps. A gap can hold any nodes, except that
+///
+/// - `^X` means no X between the previous and the next step. Before the first step it covers
+///   everything above it, after the last one everything below the matched node.
+/// - `>` means the gap is empty, the next step is a direct child of the previous one.
+/// - When optional steps are skipped, the gaps around them become one. It keeps every `^X` of
+///   them, and is only direct if all of them were.
+///
+/// Once the whole pattern is consumed, it matches the node that completed it and everything below
+/// it that the last gap allows.
+///
+/// All the patterns are stepped together, once per node. The path can line up with a pattern in
+/// more than one way, so the matcher keeps every state each pattern could be in on a stack with
+/// an entry per depth, and leaving a node pops its entry. Most states sit in a gap that allows
+/// anything, see `FreeStates`, so entering a node only costs as much as the states it can move
+/// along or has to check, rather than the number of patterns.
+#[derive(Default)]
+pub struct BreadcrumbsMatcher {
+    patterns: Vec<Pattern>,
+    frames: Frames,
+    free: FreeStates,
+    //The patterns the last node entered completed, rather than matched because an ancestor did
+    completed: Vec<usize>,
+    //The states that could consume the node being entered, kept to reuse its allocation
+    moving: Vec<State>,
+}
+
+impl BreadcrumbsMatcher {
+    pub fn new() -> Self {
+        let mut matcher = Self::default();
+        matcher.reset();
+        matcher
+    }
+
+    /// Compiles `nodes` and adds it to the patterns, returning its index. Every node name in it
+    /// has to match at least one node kind.
+    pub fn add_pattern(&mut self, nodes: Vec<MatchPattern>) -> anyhow::Result<usize> {
+        let mut pattern = Pattern::new(nodes)?;
+        pattern.first_id = self.free.positions.len();
+        self.patterns.push(pattern);
+        self.reset();
+        Ok(self.patterns.len() - 1)
+    }
+
+    /// Puts the matcher back into its initial state, so it can be driven over another tree
+    pub fn reset(&mut self) {
+        self.free = FreeStates::new(&self.patterns);
+        self.frames = Frames::default();
+        self.frames.push();
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            let state = State {
+                pattern: index,
+                position: 0,
+                since: 0,
+                looped: false,
+            };
+            pattern.add_state(&mut self.free, &mut self.frames, state);
+        }
+        self.completed.clear();
+    }
+
+    /// Steps into a node of `kind`, with its text for steps with a text predicate to look at
+    pub fn enter(&mut self, kind: NodeKind, text: Option<&str>) {
+        self.completed.clear();
+        let parent = self.frames.top_start()..self.frames.restricted.len();
+        self.frames.push();
 
-        if self.current_match == 0 {
-            return;
+        //Staying in the gap keeps a state as it is, which free states do without being copied.
+        //States skipping optional steps from it are in the gaps after those, and were checked
+        //against them on their own.
+        for index in parent.clone() {
+            let state = self.frames.restricted[index];
+            if self.patterns[state.pattern].gap_allows(&state, kind)
+                && !self.frames.top().contains(&state)
+            {
+                self.frames.restricted.push(state);
+            }
         }
 
-        while let Some(MatchPattern::NotMatches(p)) = self.pattern.get(self.current_match - 1) {
-            #[cfg(test)]
-            println!(
-                "LOOP : {:?}, {}, {:?}",
-                self.pattern.get(self.current_match - 1),
-                self.current_match,
-                self.current_notmatch
-            );
-            if let Some(n) = self.current_notmatch.last() {
-                if *n == self.current_match - 1 {
-                    if *p != node_tostring {
-                        return;
-                    } else {
-                        self.current_notmatch.pop();
-                    }
+        let waiting = self.free.waiting[kind as usize]
+            .iter()
+            .chain(&self.free.waiting_any)
+            .map(|id| {
+                let (pattern, position) = self.free.positions[*id];
+                State {
+                    pattern,
+                    position,
+                    since: position,
+                    looped: false,
                 }
+            });
+        let mut moving = std::mem::take(&mut self.moving);
+        moving.clear();
+        moving.extend(&self.frames.restricted[parent]);
+        moving.extend(waiting);
+
+        for state in moving.iter().copied() {
+            let pattern = &self.patterns[state.pattern];
+            let Some(step) = pattern.steps.get(state.position) else {
+                continue;
+            };
+            if step
+                .kinds
+                .as_ref()
+                .is_some_and(|kinds| !kinds.contains(kind))
+            {
+                continue;
+            }
+            if step
+                .text
+                .as_ref()
+                .is_some_and(|predicate| !text.is_some_and(|text| predicate.matches(text)))
+            {
+                continue;
             }
-            self.current_match -= 1;
-            if self.current_match == 0 {
-                assert!(
-                    self.current_notmatch.len() == 0,
-                    "Notmatch needs to be empty at level 0"
+            let next = state.position + 1;
+            if pattern.steps[next..].iter().all(|step| step.optional)
+                && !self.completed.contains(&state.pattern)
+            {
+                self.completed.push(state.pattern);
+            }
+            pattern.add_state(
+                &mut self.free,
+                &mut self.frames,
+                State {
+                    position: next,
+                    since: next,
+                    looped: false,
+                    ..state
+                },
+            );
+            if step.repeats {
+                pattern.add_state(
+                    &mut self.free,
+                    &mut self.frames,
+                    State {
+                        since: state.position,
+                        looped: true,
+                        ..state
+                    },
                 );
-                return;
             }
         }
+
+        self.moving = moving;
+        self.completed.sort_unstable();
+    }
+
+    pub fn leave(&mut self) {
+        if self.frames.starts.len() > 1 {
+            let (restricted, activated) = self.frames.starts.pop().unwrap();
+            self.frames.restricted.truncate(restricted);
+            for id in self.frames.activated.drain(activated..).rev() {
+                let pattern = &self.patterns[self.free.positions[id].0];
+                self.free.deactivate(pattern, id);
+            }
+        }
+        self.completed.clear();
+    }
+
+    /// The indices of the patterns matching the current node, ascending
+    pub fn matched(&self) -> impl Iterator<Item = usize> + '_ {
+        let restricted = self
+            .frames
+            .top()
+            .iter()
+            .filter(|state| self.patterns[state.pattern].accepts(state))
+            .map(|state| state.pattern);
+        let free = self
+            .free
+            .accepting
+            .iter()
+            .map(|id| self.free.positions[*id].0);
+        restricted.chain(free).sorted_unstable().dedup()
+    }
+
+    /// The indices of the patterns whose last step the node just entered consumed, ascending
+    pub fn completed(&self) -> &[usize] {
+        &self.completed
     }
 
     pub fn matches(&self) -> bool {
-        return self.current_match == self.pattern.len() && self.current_notmatch.len() == 0;
+        self.matched().next().is_some()
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
-    #[cfg(test)]
     enum Event<'a> {
         Enter(&'a str),
+        //Leaving doesn't need the node, it is only there to keep the stimulus readable
+        #[allow(dead_code)]
         Leave(&'a str),
     }
 
+    fn kind(name: &str) -> NodeKind {
+        NodeKind::matching(name)[0]
+    }
+
+    fn matcher(patterns: Vec<Vec<MatchPattern>>) -> BreadcrumbsMatcher {
+        let mut matcher = BreadcrumbsMatcher::new();
+        for pattern in patterns {
+            matcher.add_pattern(pattern).unwrap();
+        }
+        matcher
+    }
+
     #[test]
     fn matcher_test_notmatch_without_next() {
         let stim: Vec<(bool, Event)> = vec![
-            (false, Event::Enter("Prebase")),
-            (false, Event::Enter("Base")),
-            (false, Event::Enter("NextLevel")),
-            (false, Event::Enter("DisallowedLevel")),
-            (false, Event::Enter("NotMatched")),
-            (false, Event::Leave("NotMatched")),
-            (false, Event::Leave("DisallowedLevel")),
-            (false, Event::Leave("Base")),
-            (false, Event::Leave("Prebase")),
+            (false, Event::Enter("SourceText")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Statement")),
+            (false, Event::Enter("Expression")),
+            (false, Event::Enter("Keyword")),
+            (false, Event::Leave("Keyword")),
+            (false, Event::Leave("Expression")),
+            (false, Event::Leave("AlwaysConstruct")),
+            (false, Event::Leave("SourceText")),
         ];
 
         let pattern = vec![
-            MatchPattern::Matches("Base"),
-            MatchPattern::NotMatches("DisallowedLevel"),
-            MatchPattern::Matches("Something"),
+            MatchPattern::Matches("AlwaysConstruct"),
+            MatchPattern::NotMatches("Expression"),
+            MatchPattern::Matches("Symbol"),
         ];
 
-        let mut bc = BreadcrumbsMatcher::new(pattern, Box::new(|_| {}));
+        let mut bc = matcher(vec![pattern]);
 
         for (matches, event) in stim {
             match event {
-                Event::Enter(e) => bc.enter(&e),
-                Event::Leave(e) => bc.leave(&e),
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
             }
             assert!(
                 bc.matches() == matches,
@@ -227,30 +806,30 @@ mod tests {
     #[test]
     fn matcher_notmatch_multiple() {
         let stim: Vec<(bool, Event)> = vec![
-            (false, Event::Enter("Base")),
-            (false, Event::Enter("DisallowedLevel")),
-            (false, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("DisallowedLevel")),
-            (false, Event::Leave("Base")),
-            (false, Event::Enter("Base")),
-            (true, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("Base")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Expression")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Expression")),
+            (false, Event::Leave("AlwaysConstruct")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("AlwaysConstruct")),
         ];
 
         let pattern = vec![
-            MatchPattern::Matches("Base"),
-            MatchPattern::NotMatches("DisallowedLevel"),
-            MatchPattern::Matches("Something"),
+            MatchPattern::Matches("AlwaysConstruct"),
+            MatchPattern::NotMatches("Expression"),
+            MatchPattern::Matches("Symbol"),
         ];
 
-        let mut bc = BreadcrumbsMatcher::new(pattern, Box::new(|_| {}));
+        let mut bc = matcher(vec![pattern]);
 
         for (matches, event) in stim {
             match event {
-                Event::Enter(e) => bc.enter(&e),
-                Event::Leave(e) => bc.leave(&e),
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
             }
             assert!(
                 bc.matches() == matches,
@@ -262,26 +841,26 @@ mod tests {
     #[test]
     fn matcher_notmatch_after() {
         let stim: Vec<(bool, Event)> = vec![
-            (false, Event::Enter("Base")),
-            (true, Event::Enter("Something")),
-            (true, Event::Enter("DisallowedLevel")),
-            (true, Event::Leave("DisallowedLevel")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("Base")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (true, Event::Enter("Symbol")),
+            (true, Event::Enter("Expression")),
+            (true, Event::Leave("Expression")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("AlwaysConstruct")),
         ];
 
         let pattern = vec![
-            MatchPattern::Matches("Base"),
-            MatchPattern::NotMatches("DisallowedLevel"),
-            MatchPattern::Matches("Something"),
+            MatchPattern::Matches("AlwaysConstruct"),
+            MatchPattern::NotMatches("Expression"),
+            MatchPattern::Matches("Symbol"),
         ];
 
-        let mut bc = BreadcrumbsMatcher::new(pattern, Box::new(|_| {}));
+        let mut bc = matcher(vec![pattern]);
 
         for (matches, event) in stim {
             match event {
-                Event::Enter(e) => bc.enter(&e),
-                Event::Leave(e) => bc.leave(&e),
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
             }
             assert!(
                 bc.matches() == matches,
@@ -293,49 +872,49 @@ mod tests {
     #[test]
     fn matcher_respects_not_equal_match_reentry() {
         let stim: Vec<(bool, Event)> = vec![
-            (false, Event::Enter("Base")),
-            (false, Event::Enter("NextLevel")),
-            (false, Event::Enter("AllowedLevel")),
-            (true, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (true, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Enter("DisallowedLevel")),
-            (false, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("DisallowedLevel")),
-            (false, Event::Leave("AllowedLevel")),
-            (false, Event::Leave("NextLevel")),
-            (false, Event::Leave("Base")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Statement")),
+            (false, Event::Enter("SeqBlock")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Enter("Expression")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Expression")),
+            (false, Event::Leave("SeqBlock")),
+            (false, Event::Leave("Statement")),
+            (false, Event::Leave("AlwaysConstruct")),
             //Now we redo the above
-            (false, Event::Enter("Base")),
-            (false, Event::Enter("NextLevel")),
-            (false, Event::Enter("AllowedLevel")),
-            (true, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (true, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Enter("DisallowedLevel")),
-            (false, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("DisallowedLevel")),
-            (false, Event::Leave("AllowedLevel")),
-            (false, Event::Leave("NextLevel")),
-            (false, Event::Leave("Base")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Statement")),
+            (false, Event::Enter("SeqBlock")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Enter("Expression")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Expression")),
+            (false, Event::Leave("SeqBlock")),
+            (false, Event::Leave("Statement")),
+            (false, Event::Leave("AlwaysConstruct")),
         ];
 
         let pattern = vec![
-            MatchPattern::Matches("Base"),
-            MatchPattern::NotMatches("DisallowedLevel"),
-            MatchPattern::Matches("Something"),
+            MatchPattern::Matches("AlwaysConstruct"),
+            MatchPattern::NotMatches("Expression"),
+            MatchPattern::Matches("Symbol"),
         ];
 
-        let mut bc = BreadcrumbsMatcher::new(pattern, Box::new(|_| {}));
+        let mut bc = matcher(vec![pattern]);
 
         for (matches, event) in stim {
             match event {
-                Event::Enter(e) => bc.enter(&e),
-                Event::Leave(e) => bc.leave(&e),
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
             }
             assert!(
                 bc.matches() == matches,
@@ -347,24 +926,24 @@ mod tests {
     #[test]
     fn matcher_test_ignores_nomatch() {
         let stim: Vec<(bool, Event)> = vec![
-            (false, Event::Enter("Base")),
-            (true, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("Base")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("AlwaysConstruct")),
         ];
 
         let pattern = vec![
-            MatchPattern::Matches("Base"),
-            MatchPattern::NotMatches("DisallowedLevel"),
-            MatchPattern::Matches("Something"),
+            MatchPattern::Matches("AlwaysConstruct"),
+            MatchPattern::NotMatches("Expression"),
+            MatchPattern::Matches("Symbol"),
         ];
 
-        let mut bc = BreadcrumbsMatcher::new(pattern, Box::new(|_| {}));
+        let mut bc = matcher(vec![pattern]);
 
         for (matches, event) in stim {
             match event {
-                Event::Enter(e) => bc.enter(&e),
-                Event::Leave(e) => bc.leave(&e),
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
             }
             assert!(
                 bc.matches() == matches,
@@ -376,26 +955,26 @@ mod tests {
     #[test]
     fn matcher_respects_not_equal_match() {
         let stim: Vec<(bool, Event)> = vec![
-            (false, Event::Enter("Base")),
-            (false, Event::Enter("NextLevel")),
-            (false, Event::Enter("AllowedLevel")),
-            (true, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("AllowedLevel")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Statement")),
+            (false, Event::Enter("SeqBlock")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("SeqBlock")),
         ];
 
         let pattern = vec![
-            MatchPattern::Matches("Base"),
-            MatchPattern::NotMatches("DisallowedLevel"),
-            MatchPattern::Matches("Something"),
+            MatchPattern::Matches("AlwaysConstruct"),
+            MatchPattern::NotMatches("Expression"),
+            MatchPattern::Matches("Symbol"),
         ];
 
-        let mut bc = BreadcrumbsMatcher::new(pattern, Box::new(|_| {}));
+        let mut bc = matcher(vec![pattern]);
 
         for (matches, event) in stim {
             match event {
-                Event::Enter(e) => bc.enter(&e),
-                Event::Leave(e) => bc.leave(&e),
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
             }
             assert!(
                 bc.matches() == matches,
@@ -407,26 +986,26 @@ mod tests {
     #[test]
     fn matcher_respects_not_equal_notmatch() {
         let stim: Vec<(bool, Event)> = vec![
-            (false, Event::Enter("Base")),
-            (false, Event::Enter("NextLevel")),
-            (false, Event::Enter("DisallowedLevel")),
-            (false, Event::Enter("Something")),
-            (false, Event::Leave("Something")),
-            (false, Event::Leave("DisallowedLevel")),
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Statement")),
+            (false, Event::Enter("Expression")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Expression")),
         ];
 
         let pattern = vec![
-            MatchPattern::Matches("Base"),
-            MatchPattern::NotMatches("DisallowedLevel"),
-            MatchPattern::Matches("Something"),
+            MatchPattern::Matches("AlwaysConstruct"),
+            MatchPattern::NotMatches("Expression"),
+            MatchPattern::Matches("Symbol"),
         ];
 
-        let mut bc = BreadcrumbsMatcher::new(pattern, Box::new(|_| {}));
+        let mut bc = matcher(vec![pattern]);
 
         for (matches, event) in stim {
             match event {
-                Event::Enter(e) => bc.enter(&e),
-                Event::Leave(e) => bc.leave(&e),
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
             }
             assert!(
                 bc.matches() == matches,
@@ -434,4 +1013,539 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn parse_pattern_wildcards_and_repetition() {
+        let pattern =
+            parse_pattern("Base ^Disallowed ? *  Identifier{1,2} ?{,3} Locate{2,}").unwrap();
+
+        assert_eq!(
+            pattern,
+            vec![
+                MatchPattern::Matches("Base"),
+                MatchPattern::NotMatches("Disallowed"),
+                MatchPattern::Any,
+                MatchPattern::Repeat(Box::new(MatchPattern::Any), 0, None),
+                MatchPattern::Repeat(Box::new(MatchPattern::Matches("Identifier")), 1, Some(2)),
+                MatchPattern::Repeat(Box::new(MatchPattern::Any), 0, Some(3)),
+                MatchPattern::Repeat(Box::new(MatchPattern::Matches("Locate")), 2, None),
+            ]
+        );
+        assert!(parse_pattern("^Disallowed{1,2}").is_err());
+        assert!(parse_pattern("Base{3,1}").is_err());
+        assert!(parse_pattern("Base{a}").is_err());
+    }
+
+    #[test]
+    fn matcher_rejects_unknown_node_kinds() {
+        let mut bc = BreadcrumbsMatcher::new();
+        let error = bc
+            .add_pattern(parse_pattern("AlwaysConstruct ^Expresion Locate").unwrap())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown node kind \"Expresion\", did you mean \"Expression\"?"
+        );
+
+        let error = bc
+            .add_pattern(parse_pattern("Xyz* Locate").unwrap())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "\"Xyz*\" doesn't match any node kind");
+        assert!(bc
+            .add_pattern(parse_pattern("*Identifier Locate").unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn matcher_any_needs_a_node_between() {
+        let stim: Vec<(bool, Event)> = vec![
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Enter("Statement")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Statement")),
+            (false, Event::Leave("AlwaysConstruct")),
+        ];
+
+        let pattern = parse_pattern("AlwaysConstruct ? Symbol").unwrap();
+        let mut bc = matcher(vec![pattern]);
+
+        for (matches, event) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert!(
+                bc.matches() == matches,
+                "Incorrectly computed matched/mismatch"
+            );
+        }
+    }
+
+    #[test]
+    fn matcher_repetition_is_a_direct_run() {
+        let stim: Vec<(bool, Event)> = vec![
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("StatementItem")),
+            (false, Event::Enter("Keyword")),
+            (false, Event::Enter("StatementItem")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Enter("StatementItem")),
+            (false, Event::Enter("Keyword")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Keyword")),
+            (false, Event::Leave("StatementItem")),
+            (false, Event::Leave("StatementItem")),
+            (false, Event::Leave("Keyword")),
+            (false, Event::Leave("StatementItem")),
+            (false, Event::Leave("AlwaysConstruct")),
+        ];
+
+        let pattern = parse_pattern("AlwaysConstruct StatementItem{2} Symbol").unwrap();
+        let mut bc = matcher(vec![pattern]);
+
+        for (matches, event) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert!(
+                bc.matches() == matches,
+                "Incorrectly computed matched/mismatch"
+            );
+        }
+    }
+
+    #[test]
+    fn matcher_direct_child() {
+        let stim: Vec<(bool, Event)> = vec![
+            (false, Event::Enter("AlwaysConstruct")),
+            (false, Event::Enter("Statement")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Statement")),
+            (true, Event::Enter("Symbol")),
+            (true, Event::Enter("Statement")),
+            (true, Event::Enter("Symbol")),
+            (true, Event::Leave("Symbol")),
+            (true, Event::Leave("Statement")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("AlwaysConstruct")),
+        ];
+
+        let pattern = parse_pattern("AlwaysConstruct > Symbol").unwrap();
+        let mut bc = matcher(vec![pattern]);
+
+        for (matches, event) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert!(
+                bc.matches() == matches,
+                "Incorrectly computed matched/mismatch"
+            );
+        }
+    }
+
+    #[test]
+    fn matcher_limits_depth() {
+        let stim: Vec<(bool, Event)> = vec![
+            (false, Event::Enter("AlwaysConstruct")),
+            (true, Event::Enter("Locate")),
+            (false, Event::Leave("Locate")),
+            (false, Event::Enter("ModuleItem")),
+            (false, Event::Enter("Primary")),
+            (true, Event::Enter("Locate")),
+            (false, Event::Leave("Locate")),
+            (false, Event::Enter("Number")),
+            (false, Event::Enter("Locate")),
+            (false, Event::Leave("Locate")),
+            (false, Event::Leave("Number")),
+            (false, Event::Leave("Primary")),
+            (false, Event::Leave("ModuleItem")),
+            (false, Event::Leave("AlwaysConstruct")),
+        ];
+
+        let pattern = parse_pattern("AlwaysConstruct > ?{0,2} > Locate").unwrap();
+        let mut bc = matcher(vec![pattern]);
+
+        for (matches, event) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert!(
+                bc.matches() == matches,
+                "Incorrectly computed matched/mismatch"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_pattern_alternation() {
+        assert_eq!(
+            parse_pattern("(Base|Other) ^(A|B) Locate{1,2}").unwrap(),
+            vec![
+                MatchPattern::OneOf(vec!["Base", "Other"]),
+                MatchPattern::NotMatches("A"),
+                MatchPattern::NotMatches("B"),
+                MatchPattern::Repeat(Box::new(MatchPattern::Matches("Locate")), 1, Some(2)),
+            ]
+        );
+        assert!(parse_pattern("(Base | Other)").is_err());
+        assert!(parse_pattern("(Base||Other)").is_err());
+        assert!(parse_pattern("Base|Other").is_err());
+    }
+
+    #[test]
+    fn matcher_alternation() {
+        let stim: Vec<(bool, Event)> = vec![
+            (false, Event::Enter("AlwaysConstruct")),
+            (true, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (true, Event::Enter("Keyword")),
+            (false, Event::Leave("Keyword")),
+            (false, Event::Enter("ModuleItem")),
+            (false, Event::Enter("Keyword")),
+            (false, Event::Leave("Keyword")),
+            (false, Event::Leave("ModuleItem")),
+            (false, Event::Enter("Primary")),
+            (false, Event::Enter("Symbol")),
+            (false, Event::Leave("Symbol")),
+            (false, Event::Leave("Primary")),
+            (false, Event::Leave("AlwaysConstruct")),
+        ];
+
+        let pattern =
+            parse_pattern("AlwaysConstruct ^(ModuleItem|Primary) (Symbol|Keyword)").unwrap();
+        let mut bc = matcher(vec![pattern]);
+
+        for (matches, event) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert!(
+                bc.matches() == matches,
+                "Incorrectly computed matched/mismatch"
+            );
+        }
+    }
+
+    #[test]
+    fn glob_matches_node_names() {
+        assert!(glob_matches("*Identifier", "PortIdentifier"));
+        assert!(glob_matches("Hierarchical*", "HierarchicalIdentifier"));
+        assert!(glob_matches("Port*Identifier", "PortIdentifier"));
+        assert!(glob_matches("*Net*", "NetDeclaration"));
+        assert!(glob_matches("Locate", "Locate"));
+        assert!(!glob_matches("Locate", "Locates"));
+        assert!(!glob_matches("*Identifier", "IdentifierList"));
+        assert!(!glob_matches("Ab*ba", "Aba"));
+    }
+
+    #[test]
+    fn matcher_globs() {
+        let stim: Vec<(bool, Event)> = vec![
+            (false, Event::Enter("AlwaysConstruct")),
+            (true, Event::Enter("PortIdentifier")),
+            (false, Event::Leave("PortIdentifier")),
+            (false, Event::Enter("DataDeclaration")),
+            (false, Event::Enter("NetIdentifier")),
+            (false, Event::Leave("NetIdentifier")),
+            (false, Event::Leave("DataDeclaration")),
+            (true, Event::Enter("Identifier")),
+            (false, Event::Leave("Identifier")),
+            (false, Event::Enter("ListOfPortIdentifiers")),
+            (false, Event::Leave("ListOfPortIdentifiers")),
+            (false, Event::Leave("AlwaysConstruct")),
+        ];
+
+        let pattern = parse_pattern("AlwaysConstruct ^*Declaration *Identifier").unwrap();
+        let mut bc = matcher(vec![pattern]);
+
+        for (matches, event) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert!(
+                bc.matches() == matches,
+                "Incorrectly computed matched/mismatch"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_pattern_text_predicates() {
+        let pattern = parse_pattern(r#"Port (A|B)[="a b\"]"] Locate[/^i_[a-z]\/x/]{1,2}"#).unwrap();
+
+        assert_eq!(pattern.len(), 3);
+        let MatchPattern::WithText(node, TextPredicate::Equals(text)) = &pattern[1] else {
+            panic!("Expected a text predicate, got {:?}", pattern[1]);
+        };
+        assert_eq!(**node, MatchPattern::OneOf(vec!["A", "B"]));
+        assert_eq!(text, "a b\"]");
+
+        let MatchPattern::Repeat(node, 1, Some(2)) = &pattern[2] else {
+            panic!("Expected a repetition, got {:?}", pattern[2]);
+        };
+        let MatchPattern::WithText(_, TextPredicate::Regex(regex)) = &**node else {
+            panic!("Expected a text predicate, got {node:?}");
+        };
+        assert_eq!(regex.as_str(), "^i_[a-z]/x");
+
+        assert!(parse_pattern("Locate[=input]").is_err());
+        assert!(parse_pattern("Locate[/i_").is_err());
+        assert!(parse_pattern("^Locate[/i_/]").is_err());
+    }
+
+    #[test]
+    fn matcher_text_predicates() {
+        let stim: Vec<(bool, Event, Option<&str>)> = vec![
+            (false, Event::Enter("PortDeclaration"), None),
+            (true, Event::Enter("Locate"), Some("i_clk")),
+            (false, Event::Leave("Locate"), None),
+            (false, Event::Enter("Locate"), Some("o_data")),
+            (false, Event::Leave("Locate"), None),
+            (false, Event::Enter("Locate"), None),
+            (false, Event::Leave("Locate"), None),
+            (false, Event::Leave("PortDeclaration"), None),
+        ];
+
+        let pattern = parse_pattern("PortDeclaration Locate[/^i_/]").unwrap();
+        let mut bc = matcher(vec![pattern]);
+
+        for (matches, event, text) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), text),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert!(
+                bc.matches() == matches,
+                "Incorrectly computed matched/mismatch"
+            );
+        }
+    }
+
+    #[test]
+    fn matcher_steps_patterns_together() {
+        let stim: Vec<(Vec<usize>, Event)> = vec![
+            (vec![], Event::Enter("AlwaysConstruct")),
+            (vec![0], Event::Enter("Symbol")),
+            (vec![0, 1, 2], Event::Enter("Keyword")),
+            (vec![0], Event::Leave("Keyword")),
+            (vec![0, 2], Event::Enter("Expression")),
+            (vec![0, 2], Event::Enter("Keyword")),
+            (vec![0, 2], Event::Leave("Keyword")),
+            (vec![0], Event::Leave("Expression")),
+            (vec![], Event::Leave("Symbol")),
+            (vec![], Event::Leave("AlwaysConstruct")),
+        ];
+
+        let patterns = [
+            "AlwaysConstruct Symbol",
+            "AlwaysConstruct ^Expression Keyword",
+            "Symbol ? *",
+        ];
+        let mut bc = matcher(
+            patterns
+                .iter()
+                .map(|pattern| parse_pattern(pattern).unwrap())
+                .collect(),
+        );
+
+        for (matched, event) in stim {
+            match event {
+                Event::Enter(e) => bc.enter(kind(e), None),
+                Event::Leave(_) => bc.leave(),
+            }
+            assert_eq!(bc.matched().collect::<Vec<_>>(), matched);
+        }
+    }
+
+    /// A gap of a pattern with every repetition count chosen, made of the gaps around skipped steps
+    #[derive(Clone)]
+    struct ReferenceGap(Vec<(Vec<NodeKind>, bool)>);
+
+    impl ReferenceGap {
+        fn allows(&self, nodes: &[(NodeKind, Option<&str>)]) -> bool {
+            let direct = self.0.iter().all(|(_, direct)| *direct);
+            (nodes.is_empty() || !direct)
+                && nodes
+                    .iter()
+                    .all(|(kind, _)| !self.0.iter().any(|(forbidden, _)| forbidden.contains(kind)))
+        }
+    }
+
+    fn step_matches(step: &MatchPattern, (kind, text): (NodeKind, Option<&str>)) -> bool {
+        match step {
+            MatchPattern::Matches(name) => name == &kind.name(),
+            MatchPattern::OneOf(names) => names.contains(&kind.name()),
+            MatchPattern::Any => true,
+            MatchPattern::WithText(step, TextPredicate::Equals(expected)) => {
+                text == Some(expected.as_str()) && step_matches(step, (kind, text))
+            }
+            other => unreachable!("{other:?} isn't a step"),
+        }
+    }
+
+    /// Every way to choose the repetition counts of `pattern`, up to `max_steps` steps, as the
+    /// steps and the gaps around them
+    fn reference_expand<'p, 'a>(
+        pattern: &'p [MatchPattern<'a>],
+        max_steps: usize,
+    ) -> Vec<(Vec<&'p MatchPattern<'a>>, Vec<ReferenceGap>)> {
+        let mut expansions = vec![(vec![], vec![ReferenceGap(vec![(vec![], false)])])];
+        for node in pattern {
+            let mut next = vec![];
+            for (steps, mut gaps) in expansions {
+                let open = &mut gaps.last_mut().unwrap().0;
+                match node {
+                    MatchPattern::NotMatches(name) => {
+                        open.last_mut().unwrap().0.push(kind(name));
+                        next.push((steps, gaps));
+                        continue;
+                    }
+                    MatchPattern::DirectChild => {
+                        open.last_mut().unwrap().1 = true;
+                        next.push((steps, gaps));
+                        continue;
+                    }
+                    _ => (),
+                }
+                let (step, min, max) = match node {
+                    MatchPattern::Repeat(step, min, max) => (&**step, *min, *max),
+                    step => (step, 1, Some(1)),
+                };
+                for count in min..=max.unwrap_or(max_steps).min(max_steps) {
+                    let (mut steps, mut gaps) = (steps.clone(), gaps.clone());
+                    if count == 0 {
+                        gaps.last_mut().unwrap().0.push((vec![], false));
+                    }
+                    for index in 0..count {
+                        if index > 0 {
+                            gaps.push(ReferenceGap(vec![(vec![], true)]));
+                        }
+                        steps.push(step);
+                    }
+                    if count > 0 {
+                        gaps.push(ReferenceGap(vec![(vec![], false)]));
+                    }
+                    next.push((steps, gaps));
+                }
+            }
+            expansions = next;
+        }
+        expansions
+    }
+
+    /// Whether some choice of nodes on `path` consumes the steps in order, with every gap allowing
+    /// the nodes in it
+    fn reference_lines_up(
+        steps: &[&MatchPattern],
+        gaps: &[ReferenceGap],
+        path: &[(NodeKind, Option<&str>)],
+    ) -> bool {
+        let Some((step, steps)) = steps.split_first() else {
+            return gaps[0].allows(path);
+        };
+        (0..path.len()).any(|index| {
+            gaps[0].allows(&path[..index])
+                && step_matches(step, path[index])
+                && reference_lines_up(steps, &gaps[1..], &path[index + 1..])
+        })
+    }
+
+    #[test]
+    fn matcher_agrees_with_reference() {
+        const KINDS: [&str; 4] = ["AlwaysConstruct", "Statement", "Expression", "Locate"];
+        const TEXTS: [Option<&str>; 3] = [Some("a"), Some("b"), None];
+        const MAX_DEPTH: usize = 7;
+
+        //xorshift, so every run sees the same sequences
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut random = |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound as u64) as usize
+        };
+
+        for _ in 0..200 {
+            let mut patterns = vec![];
+            for _ in 0..3 {
+                let mut pattern = vec![];
+                for _ in 0..1 + random(3) {
+                    match random(4) {
+                        0 => pattern.push(MatchPattern::NotMatches(KINDS[random(4)])),
+                        1 => pattern.push(MatchPattern::DirectChild),
+                        _ => (),
+                    }
+                    let mut step = match random(4) {
+                        0 => MatchPattern::Any,
+                        1 => MatchPattern::OneOf(vec![KINDS[random(4)], KINDS[random(4)]]),
+                        _ => MatchPattern::Matches(KINDS[random(4)]),
+                    };
+                    if random(4) == 0 {
+                        step = MatchPattern::WithText(
+                            Box::new(step),
+                            TextPredicate::Equals("a".into()),
+                        );
+                    }
+                    let bounds = [
+                        (0, Some(1)),
+                        (1, Some(2)),
+                        (0, Some(2)),
+                        (0, None),
+                        (2, None),
+                    ];
+                    if random(3) == 0 {
+                        let (min, max) = bounds[random(bounds.len())];
+                        step = MatchPattern::Repeat(Box::new(step), min, max);
+                    }
+                    pattern.push(step);
+                }
+                if random(4) == 0 {
+                    pattern.push(MatchPattern::NotMatches(KINDS[random(4)]));
+                }
+                patterns.push(pattern);
+            }
+
+            let mut bc = matcher(patterns.clone());
+            let expansions = patterns
+                .iter()
+                .map(|pattern| reference_expand(pattern, MAX_DEPTH))
+                .collect::<Vec<_>>();
+            let mut path = vec![];
+            for _ in 0..40 {
+                if !path.is_empty() && (path.len() == MAX_DEPTH || random(3) == 0) {
+                    path.pop();
+                    bc.leave();
+                } else {
+                    let node = (kind(KINDS[random(4)]), TEXTS[random(3)]);
+                    path.push(node);
+                    bc.enter(node.0, node.1);
+                }
+
+                let expected = (0..patterns.len())
+                    .filter(|index| {
+                        expansions[*index]
+                            .iter()
+                            .any(|(steps, gaps)| reference_lines_up(steps, gaps, &path))
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    bc.matched().collect::<Vec<_>>(),
+                    expected,
+                    "{patterns:?} on {path:?}"
+                );
+            }
+        }
+    }
 }
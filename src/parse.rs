@@ -0,0 +1,383 @@
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use sv_parser::{parse_sv_str, Define, DefineText, Defines, SyntaxTree};
+
+/// Keywords that start a design unit when they open a line, and the keywords ending them
+const DESIGN_UNITS: [(&str, &str); 9] = [
+    ("module", "endmodule"),
+    ("macromodule", "endmodule"),
+    ("interface", "endinterface"),
+    ("program", "endprogram"),
+    ("package", "endpackage"),
+    ("class", "endclass"),
+    ("checker", "endchecker"),
+    ("primitive", "endprimitive"),
+    ("config", "endconfig"),
+];
+
+/// Everything sv-parser's preprocessor is given besides the code itself
+pub struct ParseOptions {
+    pub defines: Defines,
+    pub incdirs: Vec<PathBuf>,
+}
+
+impl ParseOptions {
+    pub fn new(defines: &[(String, Option<String>)], incdirs: &[PathBuf]) -> Self {
+        let defines = defines
+            .iter()
+            .map(|(name, value)| {
+                let text = value.clone().map(|value| DefineText::new(value, None));
+                let define = Define::new(name.clone(), vec![], text);
+                (name.clone(), Some(define))
+            })
+            .collect();
+
+        Self {
+            defines,
+            incdirs: incdirs.to_vec(),
+        }
+    }
+}
+
+/// Why parsing failed, and where in the code if the failure was in the code itself
+#[derive(Debug)]
+pub struct ParseFailure {
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+impl ParseFailure {
+    fn new(error: &sv_parser::Error, code_path: &Path) -> Self {
+        let offset = match error {
+            sv_parser::Error::Parse(Some((path, offset)))
+            | sv_parser::Error::Preprocess(Some((path, offset)))
+                if path == code_path =>
+            {
+                Some(*offset)
+            }
+            _ => None,
+        };
+        Self {
+            offset,
+            message: Self::describe(error, code_path),
+        }
+    }
+
+    fn describe(error: &sv_parser::Error, code_path: &Path) -> String {
+        let (kind, position) = match error {
+            sv_parser::Error::Parse(position) => ("syntax error", position),
+            sv_parser::Error::Preprocess(position) => ("preprocessor error", position),
+            sv_parser::Error::Include { source } => {
+                return format!("in included file: {}", Self::describe(source, code_path))
+            }
+            other => return other.to_string(),
+        };
+        match position {
+            Some((path, offset)) if path != code_path => {
+                //Positions in other files are only useful to a person, so read those lazily
+                let position = fs::read_to_string(path)
+                    .ok()
+                    .filter(|text| *offset <= text.len())
+                    .map(|text| {
+                        let (line, col) = line_col(&text, *offset);
+                        format!("{}:{}", line, col + 1)
+                    })
+                    .unwrap_or_else(|| format!("byte {offset}"));
+                format!("{kind} in {}:{position}", path.display())
+            }
+            _ => kind.to_string(),
+        }
+    }
+
+    /// The token in `code` the failure points at, up to the next whitespace
+    pub fn token_range(&self, code: &str) -> Option<Range<usize>> {
+        let offset = self.offset.filter(|offset| *offset < code.len())?;
+        let token_len = code[offset..]
+            .find(char::is_whitespace)
+            .unwrap_or(code.len() - offset);
+        (token_len > 0).then_some(offset..offset + token_len)
+    }
+}
+
+/// The 1 based line and 0 based byte column of `offset` in `code`
+pub fn line_col(code: &str, offset: usize) -> (usize, usize) {
+    let line_start = code[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        code[..offset].matches('\n').count() + 1,
+        offset - line_start,
+    )
+}
+
+/// Runs sv-parser's preprocessor and parser over `code`, which is named `code_path`
+pub fn parse_code(
+    code: &str,
+    code_path: &str,
+    options: &ParseOptions,
+) -> Result<SyntaxTree, sv_parser::Error> {
+    let mut incdirs = vec![];
+    if let Some(dir) = Path::new(code_path).parent() {
+        incdirs.push(dir.to_path_buf());
+    }
+    incdirs.extend(options.incdirs.iter().cloned());
+
+    let (tree, _) = parse_sv_str(
+        code,
+        PathBuf::from(code_path),
+        &options.defines,
+        &incdirs,
+        false,
+        false,
+    )?;
+    Ok(tree)
+}
+
+/// Parses `code` like `parse_code`, but when the whole of it doesn't parse, every top level
+/// design unit is parsed on its own instead. Returns the trees of everything that did parse,
+/// together with the failure of every unit that didn't.
+///
+/// Each unit is parsed with the rest of the code blanked out, so offsets in every tree still
+/// line up with `code`. Directives before the first unit are kept for all of them.
+pub fn parse_recovering(
+    code: &str,
+    code_path: &str,
+    options: &ParseOptions,
+) -> (Vec<SyntaxTree>, Vec<ParseFailure>) {
+    let error = match parse_code(code, code_path, options) {
+        Ok(tree) => return (vec![tree], vec![]),
+        Err(error) => error,
+    };
+
+    let units = design_units(code);
+    let header = 0..units.first().map_or(code.len(), |unit| unit.start);
+
+    let mut trees = vec![];
+    let mut failures = vec![];
+    for (index, unit) in units.iter().enumerate() {
+        let mut keep = if index == 0 {
+            vec![header.clone()]
+        } else {
+            directive_lines(code, header.clone())
+        };
+        keep.push(unit.clone());
+
+        match parse_code(&blank_outside(code, &keep), code_path, options) {
+            Ok(tree) => trees.push(tree),
+            Err(error) => failures.push(ParseFailure::new(&error, Path::new(code_path))),
+        }
+    }
+
+    //The units can all parse on their own when the problem lies in between them
+    if failures.is_empty() {
+        failures.push(ParseFailure::new(&error, Path::new(code_path)));
+    }
+    (trees, failures)
+}
+
+/// Splits `code` at every line opening a design unit outside of any other one. Each unit runs
+/// until the next one starts, so anything trailing it is parsed along with it. Comments and
+/// attribute instances on the lines before a unit start it instead.
+fn design_units(code: &str) -> Vec<Range<usize>> {
+    let blanked = blank_comments(code);
+    let mut starts = vec![];
+    let mut depth = 0usize;
+    let mut line_start = 0;
+
+    for line in blanked.split_inclusive('\n') {
+        let mut words = line
+            .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+            .filter(|word| !word.is_empty());
+
+        let mut first = words.next();
+        //`virtual class`, but not `virtual interface` declarations
+        if first == Some("virtual") {
+            first = words.next().filter(|word| *word == "class");
+        }
+        let opens = |word: &str| DESIGN_UNITS.iter().any(|(open, _)| *open == word);
+        let closes = |word: &str| DESIGN_UNITS.iter().any(|(_, close)| *close == word);
+
+        if let Some(first) = first {
+            if opens(first) {
+                if depth == 0 {
+                    let keyword = line_start + line.len() - line.trim_start().len();
+                    starts.push(unit_start(code, &blanked, keyword));
+                }
+                depth += 1;
+            }
+            if closes(first) {
+                depth = depth.saturating_sub(1);
+            }
+        }
+        for word in words {
+            if closes(word) {
+                depth = depth.saturating_sub(1);
+            }
+        }
+
+        line_start += line.len();
+    }
+
+    let mut units = vec![];
+    for (index, start) in starts.iter().enumerate() {
+        let end = starts.get(index + 1).copied().unwrap_or(code.len());
+        units.push(*start..end);
+    }
+    units
+}
+
+/// Where the unit opened at `keyword` starts: the first thing on the lines between it and the last
+/// code before it, so that comments and attribute instances in front of it come along
+fn unit_start(code: &str, blanked: &str, keyword: usize) -> usize {
+    let before = blanked[..keyword].trim_end().len();
+    let from = blanked[before..keyword]
+        .find('\n')
+        .map_or(before, |newline| before + newline + 1);
+    from + code[from..].len() - code[from..].trim_start().len()
+}
+
+/// `code` with its comments, string literals and attribute instances blanked out like
+/// `blank_outside` does, so that only the code around them is left to find words in
+fn blank_comments(code: &str) -> String {
+    let mut output = String::with_capacity(code.len());
+    let mut rest = code;
+    while let Some(c) = rest.chars().next() {
+        let closed_by = |open: &str, close: &str| {
+            rest[open.len()..]
+                .find(close)
+                .map_or(rest.len(), |end| open.len() + end + close.len())
+        };
+        let end = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if rest.starts_with("/*") {
+            closed_by("/*", "*/")
+        } else if rest.starts_with("(*") && !rest[2..].trim_start().starts_with(')') {
+            //`(*)` is the wildcard of a sensitivity list instead
+            closed_by("(*", "*)")
+        } else if c == '"' {
+            let mut escaped = false;
+            rest.char_indices()
+                .skip(1)
+                .find(|&(_, c)| {
+                    let closes = !escaped && (c == '"' || c == '\n');
+                    escaped = !escaped && c == '\\';
+                    closes
+                })
+                .map_or(rest.len(), |(end, _)| end + 1)
+        } else {
+            output.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        for c in rest[..end].chars() {
+            match c {
+                '\n' => output.push(c),
+                _ => output.extend(std::iter::repeat_n(' ', c.len_utf8())),
+            }
+        }
+        rest = &rest[end..];
+    }
+    output
+}
+
+/// The lines in `range` holding compiler directives, including continuation lines of `define
+fn directive_lines(code: &str, range: Range<usize>) -> Vec<Range<usize>> {
+    let mut output = vec![];
+    let mut line_start = range.start;
+    let mut continued = false;
+    for line in code[range].split_inclusive('\n') {
+        if continued || line.trim_start().starts_with('`') {
+            output.push(line_start..line_start + line.len());
+            continued = line.trim_end().ends_with('\\');
+        }
+        line_start += line.len();
+    }
+    output
+}
+
+/// Replaces everything in `code` outside of `keep` with spaces, keeping newlines and byte offsets
+fn blank_outside(code: &str, keep: &[Range<usize>]) -> String {
+    let mut output = String::with_capacity(code.len());
+    for (position, c) in code.char_indices() {
+        if c == '\n' || keep.iter().any(|range| range.contains(&position)) {
+            output.push(c);
+        } else {
+            output.extend(std::iter::repeat_n(' ', c.len_utf8()));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn design_units_split_at_top_level() {
+        let code = "`timescale 1ns/1ps\n\
+                    package p;\n  class c;\n  endclass\nendpackage\n\
+                    module a; // module b\nendmodule\n\
+                    virtual class v;\n  virtual interface i vif;\nendclass\n";
+        let units = design_units(code)
+            .into_iter()
+            .map(|range| code[range].lines().next().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            units,
+            ["package p;", "module a; // module b", "virtual class v;"]
+        );
+    }
+
+    #[test]
+    fn design_units_skip_comments_and_strings() {
+        let code = "module a;\n\
+                    /* endmodule\n\
+                    module b; */\n\
+                    initial $display(\"endmodule \\\" package\");\n\
+                    endmodule\n\
+                    module c;\nendmodule\n";
+        let units = design_units(code)
+            .into_iter()
+            .map(|range| code[range].lines().next().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(units, ["module a;", "module c;"]);
+    }
+
+    #[test]
+    fn design_units_start_at_their_attributes() {
+        let code = "module a;\nalways @(*) x = y;\nendmodule\n\
+                    (* keep_hierarchy *) module b;\nendmodule\n\
+                    (* keep *)\npackage p;\nendpackage\n";
+        let units = design_units(code)
+            .into_iter()
+            .map(|range| code[range].lines().next().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            units,
+            ["module a;", "(* keep_hierarchy *) module b;", "(* keep *)"]
+        );
+    }
+
+    #[test]
+    fn recovering_keeps_units_that_parse() {
+        let code = "`define W 4\nmodule a;\nlogic [`W-1:0] x;\nendmodule\n\
+                    module b;\nlogic y\nendmodule\n\
+                    module c;\nlogic [`W-1:0] z;\nendmodule\n";
+        let options = ParseOptions::new(&[], &[]);
+        let (trees, failures) = parse_recovering(code, "test.sv", &options);
+
+        assert_eq!(trees.len(), 2);
+        assert_eq!(failures.len(), 1);
+        let range = failures[0].token_range(code).unwrap();
+        assert_eq!(&code[range], "endmodule");
+        assert_eq!(failures[0].message, "syntax error");
+    }
+
+    #[test]
+    fn blank_outside_keeps_offsets() {
+        assert_eq!(blank_outside("ab\ncdé", &[1..2, 3..4]), " b\nc   ");
+    }
+}
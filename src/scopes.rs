@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use sv_parser::{Identifier, PackageScope, RefNode, SyntaxTree};
+
+use crate::node_kind::NodeKind;
+
+/// An open scope, and the node it was opened by
+#[derive(Debug)]
+struct OpenScope {
+    id: usize,
+    depth: usize,
+    //The kind of the identifier naming the scope, which belongs to the scope around it. `None` for
+    //blocks without a label.
+    name: Option<NodeKind>,
+}
+
+/// Tracks the scopes declarations are made in while walking a tree. Modules, interfaces,
+/// programs, packages, classes, checkers, functions, tasks, generate blocks and begin/end or
+/// fork/join blocks each open a scope inside the one they are in, blocks without a label an
+/// anonymous one. Scope 0 is the file itself.
+///
+/// The packages imported into a scope are visible from it as well, and an identifier after `p::`
+/// is looked up in a scope of its own that only sees package `p`.
+#[derive(Debug)]
+pub struct Scopes {
+    //The scope around each scope, by id
+    parents: Vec<Option<usize>>,
+    //The packages imported into each scope, by id, with the one name imported from them or `None`
+    //for all of them
+    imports: Vec<Vec<(String, Option<String>)>>,
+    //The scope of each package, by name
+    packages: HashMap<String, usize>,
+    open: Vec<OpenScope>,
+    depth: usize,
+    //The depth of the identifier naming the innermost scope, while inside of it
+    naming: Option<usize>,
+    //The package named by a package or class scope, with the depth of the package scope while inside of
+    //it. Once it is left, the package is taken by the identifier after it
+    qualifier: Option<(String, Option<usize>)>,
+    //The scope of the identifier after that scope, and its depth, while inside of it
+    qualified: Option<(usize, usize)>,
+}
+
+impl Default for Scopes {
+    fn default() -> Self {
+        Self {
+            parents: vec![None],
+            imports: vec![vec![]],
+            packages: HashMap::new(),
+            open: vec![],
+            depth: 0,
+            naming: None,
+            qualifier: None,
+            qualified: None,
+        }
+    }
+}
+
+impl Scopes {
+    pub fn enter(&mut self, node: &RefNode, tree: &SyntaxTree) {
+        self.depth += 1;
+        match node {
+            RefNode::PackageImportItemIdentifier(item) => {
+                let (package, _, name) = &item.nodes;
+                if let (Some(package), Some(name)) =
+                    (identifier(tree, &package.nodes.0), identifier(tree, name))
+                {
+                    let scope = self.current();
+                    self.imports[scope].push((package.to_string(), Some(name.to_string())));
+                }
+            }
+            RefNode::PackageImportItemAsterisk(item) => {
+                if let Some(package) = identifier(tree, &item.nodes.0.nodes.0) {
+                    let scope = self.current();
+                    self.imports[scope].push((package.to_string(), None));
+                }
+            }
+            RefNode::PackageScope(PackageScope::Package(scope)) => {
+                self.qualifier = identifier(tree, &scope.nodes.0.nodes.0)
+                    .map(|package| (package.to_string(), Some(self.depth)));
+            }
+            //`p::` in expressions is parsed as a class scope, as the parser can't tell them apart
+            RefNode::ClassScope(scope) => {
+                let (class, parameters, nested) = &scope.nodes.0.nodes;
+                if class.nodes.0.is_none() && parameters.is_none() && nested.is_empty() {
+                    self.qualifier = identifier(tree, &class.nodes.1.nodes.0)
+                        .map(|package| (package.to_string(), Some(self.depth)));
+                }
+            }
+            RefNode::SimpleIdentifier(_) | RefNode::EscapedIdentifier(_) => {
+                if let Some((package, _)) = self.qualifier.take_if(|(_, depth)| depth.is_none()) {
+                    self.parents.push(None);
+                    self.imports.push(vec![(package, None)]);
+                    self.qualified = Some((self.parents.len() - 1, self.depth));
+                }
+            }
+            _ => (),
+        }
+        let kind = NodeKind::of(node);
+        if self.naming.is_none()
+            && self
+                .open
+                .last()
+                .is_some_and(|scope| scope.name == Some(kind))
+        {
+            self.naming = Some(self.depth);
+        }
+        if let Some(name) = scope_name(node) {
+            self.parents.push(Some(self.current()));
+            self.imports.push(vec![]);
+            let id = self.parents.len() - 1;
+            self.open.push(OpenScope {
+                id,
+                depth: self.depth,
+                name,
+            });
+            if let RefNode::PackageDeclaration(package) = node {
+                if let Some(package) = identifier(tree, &package.nodes.3.nodes.0) {
+                    self.packages.insert(package.to_string(), id);
+                }
+            }
+        }
+    }
+
+    pub fn leave(&mut self) {
+        if self.qualified.is_some_and(|(_, depth)| depth == self.depth) {
+            self.qualified = None;
+        }
+        if let Some((_, depth)) = &mut self.qualifier {
+            if *depth == Some(self.depth) {
+                *depth = None;
+            }
+        }
+        if self.naming == Some(self.depth) {
+            self.naming = None;
+        }
+        if self
+            .open
+            .last()
+            .is_some_and(|scope| scope.depth == self.depth)
+        {
+            self.open.pop();
+        }
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    /// The scope a declaration at the current node is made in
+    pub fn current(&self) -> usize {
+        if let Some((scope, _)) = self.qualified {
+            return scope;
+        }
+        let innermost = self.open.last().map_or(0, |scope| scope.id);
+        match self.naming {
+            Some(_) => self.parents[innermost].unwrap_or(innermost),
+            None => innermost,
+        }
+    }
+
+    /// The scopes `name` is looked up in from `scope`. That is `scope` and every scope around it,
+    /// innermost first, each followed by the packages it imports `name` from
+    pub fn visible_from<'s>(
+        &'s self,
+        scope: usize,
+        name: &'s str,
+    ) -> impl Iterator<Item = usize> + 's {
+        std::iter::successors(Some(scope), |scope| self.parents[*scope]).flat_map(move |scope| {
+            let imported = self.imports[scope]
+                .iter()
+                .filter(move |(_, only)| only.as_deref().is_none_or(|only| only == name))
+                .filter_map(|(package, _)| self.packages.get(package).copied());
+            std::iter::once(scope).chain(imported)
+        })
+    }
+}
+
+/// The text of `identifier`
+fn identifier<'t>(tree: &'t SyntaxTree, identifier: &Identifier) -> Option<&'t str> {
+    let locate = match identifier {
+        Identifier::SimpleIdentifier(identifier) => &identifier.nodes.0,
+        Identifier::EscapedIdentifier(identifier) => &identifier.nodes.0,
+    };
+    tree.get_str(locate)
+}
+
+/// Whether `node` opens a scope, with the kind of identifier naming it if it has a name
+fn scope_name(node: &RefNode) -> Option<Option<NodeKind>> {
+    Some(Some(match node {
+        RefNode::ModuleDeclaration(_) => NodeKind::ModuleIdentifier,
+        RefNode::InterfaceDeclaration(_) => NodeKind::InterfaceIdentifier,
+        RefNode::ProgramDeclaration(_) => NodeKind::ProgramIdentifier,
+        RefNode::PackageDeclaration(_) => NodeKind::PackageIdentifier,
+        RefNode::ClassDeclaration(_) => NodeKind::ClassIdentifier,
+        RefNode::CheckerDeclaration(_) => NodeKind::CheckerIdentifier,
+        RefNode::FunctionDeclaration(_) => NodeKind::FunctionIdentifier,
+        RefNode::TaskDeclaration(_) => NodeKind::TaskIdentifier,
+        RefNode::GenerateBlockMultiple(_) => NodeKind::GenerateBlockIdentifier,
+        RefNode::SeqBlock(block) if block.nodes.1.is_some() => NodeKind::BlockIdentifier,
+        RefNode::ParBlock(block) if block.nodes.1.is_some() => NodeKind::BlockIdentifier,
+        RefNode::SeqBlock(_) | RefNode::ParBlock(_) => return Some(None),
+        _ => return None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sv_parser::NodeEvent;
+
+    /// The scope of every identifier in `code` that is one of `names`, and the scopes after it
+    fn scopes_of<'n>(code: &str, names: &[&'n str]) -> (Vec<(&'n str, usize)>, Scopes) {
+        let options = crate::parse::ParseOptions::new(&[], &[]);
+        let tree = crate::parse::parse_code(code, "test.sv", &options).unwrap();
+
+        let mut scopes = Scopes::default();
+        let mut identifiers = vec![];
+        for event in tree.into_iter().event() {
+            match event {
+                NodeEvent::Enter(node) => {
+                    scopes.enter(&node, &tree);
+                    if let RefNode::Locate(locate) = node {
+                        let text = tree.get_str(locate).unwrap();
+                        if let Some(name) = names.iter().find(|name| **name == text) {
+                            identifiers.push((*name, scopes.current()));
+                        }
+                    }
+                }
+                NodeEvent::Leave(_) => scopes.leave(),
+            }
+        }
+        (identifiers, scopes)
+    }
+
+    #[test]
+    fn scope_names_belong_to_the_scope_around() {
+        let code = "module m;\nfunction void f;\nlogic c;\nendfunction\nendmodule\n";
+        let (identifiers, scopes) = scopes_of(code, &["m", "f", "c"]);
+
+        assert_eq!(identifiers, [("m", 0), ("f", 1), ("c", 2)]);
+        assert_eq!(scopes.visible_from(2, "c").collect::<Vec<_>>(), [2, 1, 0]);
+    }
+
+    #[test]
+    fn blocks_without_a_label_open_scopes() {
+        let code = "module m;
+initial begin
+logic c;
+c = 0;
+end
+initial c = 1;
+endmodule
+";
+        let (identifiers, scopes) = scopes_of(code, &["c"]);
+
+        //The declaration and the use in the block, then the use outside of it
+        assert_eq!(identifiers, [("c", 2), ("c", 2), ("c", 1)]);
+        assert_eq!(scopes.visible_from(1, "c").collect::<Vec<_>>(), [1, 0]);
+    }
+}
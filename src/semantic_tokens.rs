@@ -0,0 +1,119 @@
+use crate::syntax_matcher::{ColumnEncoding, MatchedSyntaxItem};
+use serde::Serialize;
+
+/// Highlights encoded as LSP `SemanticTokens`, along with the legend the token types index into
+#[derive(Debug, Serialize)]
+pub struct SemanticTokens<'a> {
+    pub legend: Legend<'a>,
+    pub data: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Legend<'a> {
+    pub token_types: Vec<&'a str>,
+    pub token_modifiers: Vec<&'a str>,
+}
+
+impl<'a> SemanticTokens<'a> {
+    /// Encodes `items` as relative `deltaLine deltaStartChar length tokenType tokenModifiers`
+    /// tuples. Characters are counted in `encoding`, which is UTF-16 unless the client asked for
+    /// another one. Items in groups missing from `token_types` are skipped.
+    pub fn new(
+        items: &[MatchedSyntaxItem],
+        code: &str,
+        token_types: Vec<&'a str>,
+        encoding: ColumnEncoding,
+    ) -> Self {
+        let mut tokens = items
+            .iter()
+            .filter(|item| item.col_start < item.col_end)
+            .filter_map(|item| {
+                let token_type = token_types.iter().position(|group| *group == item.group)?;
+                let line_start = item.offset_start - item.col_start;
+                let start = encoding.units(&code[line_start..item.offset_start]) as u32;
+                let length = encoding.units(item.matched) as u32;
+                Some((item.line as u32 - 1, start, length, token_type as u32))
+            })
+            .collect::<Vec<_>>();
+        tokens.sort();
+        tokens.dedup();
+
+        let mut data = Vec::with_capacity(tokens.len() * 5);
+        let (mut previous_line, mut previous_start) = (0, 0);
+        for (line, start, length, token_type) in tokens {
+            if line != previous_line {
+                previous_start = 0;
+            }
+            data.extend([
+                line - previous_line,
+                start - previous_start,
+                length,
+                token_type,
+                0,
+            ]);
+            (previous_line, previous_start) = (line, start);
+        }
+
+        Self {
+            legend: Legend {
+                token_types,
+                token_modifiers: vec![],
+            },
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item<'a>(
+        code: &'a str,
+        group: &'a str,
+        line: usize,
+        col_start: usize,
+        col_end: usize,
+    ) -> MatchedSyntaxItem<'a> {
+        let line_start = code
+            .split_inclusive('\n')
+            .take(line - 1)
+            .map(str::len)
+            .sum::<usize>();
+        MatchedSyntaxItem {
+            group,
+            line,
+            col_start,
+            col_end,
+            offset_start: line_start + col_start,
+            offset_end: line_start + col_end,
+            matched: &code[line_start + col_start..line_start + col_end],
+        }
+    }
+
+    #[test]
+    fn semantic_tokens_are_delta_encoded() {
+        let code = "module m;\n  logic é, b;\nendmodule\n";
+        let items = [
+            item(code, "denshiKeyword", 3, 0, 9),
+            item(code, "denshiIdentifier", 2, 11, 12),
+            item(code, "denshiKeyword", 1, 0, 6),
+            item(code, "denshiKeyword", 2, 2, 7),
+            item(code, "denshiUnknown", 1, 7, 8),
+        ];
+        let token_types = vec!["denshiKeyword", "denshiIdentifier"];
+        let tokens = SemanticTokens::new(&items, code, token_types, ColumnEncoding::Utf16);
+
+        #[rustfmt::skip]
+        assert_eq!(
+            tokens.data,
+            [
+                0, 0, 6, 0, 0,
+                1, 2, 5, 0, 0,
+                0, 8, 1, 1, 0,
+                1, 0, 9, 0, 0,
+            ]
+        );
+    }
+}
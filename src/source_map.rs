@@ -0,0 +1,195 @@
+use std::ops::Range;
+use std::path::Path;
+
+use sv_parser::{NodeEvent, RefNode, SyntaxTree, WhiteSpace};
+
+/// A run of preprocessed text that was copied verbatim from the code
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: usize,
+    end: usize,
+    original: usize,
+}
+
+/// Maps byte offsets in the text sv-parser parsed back to byte offsets in the code it was given.
+///
+/// Only text that appears in the code, in order, is mapped. Included files, `-D` defines and
+/// macro expansions (which sv-parser traces back to the body of the `define) have no position in
+/// the code of their own, so they are left out.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    segments: Vec<Segment>,
+}
+
+impl SourceMap {
+    pub fn new(tree: &SyntaxTree, code: &str, code_path: &Path) -> Self {
+        let mut segments: Vec<Segment> = vec![];
+        let mut space_depth = 0;
+
+        for node_event in tree.into_iter().event() {
+            let locate = match node_event {
+                NodeEvent::Enter(RefNode::WhiteSpace(WhiteSpace::Space(_))) => {
+                    space_depth += 1;
+                    continue;
+                }
+                NodeEvent::Leave(RefNode::WhiteSpace(WhiteSpace::Space(_))) => {
+                    space_depth -= 1;
+                    continue;
+                }
+                //sv-parser gives spaces an empty origin just past them, so they can't be mapped
+                NodeEvent::Enter(RefNode::Locate(locate)) if space_depth == 0 => locate,
+                _ => continue,
+            };
+
+            let Some((path, original)) = tree.get_origin(locate) else {
+                continue;
+            };
+            if path.as_path() != code_path {
+                continue;
+            }
+            if segments
+                .last()
+                .is_some_and(|last| original < last.original + (last.end - last.start))
+            {
+                continue;
+            }
+            if tree.get_str(locate) != code.get(original..original + locate.len) {
+                continue;
+            }
+
+            segments.push(Segment {
+                start: locate.offset,
+                end: locate.offset + locate.len,
+                original,
+            });
+        }
+
+        Self { segments }
+    }
+
+    /// The range in the code that `start..end` of the preprocessed text was copied from
+    pub fn original_range(&self, start: usize, end: usize) -> Option<Range<usize>> {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.end <= start);
+        let segment = self.segments.get(index)?;
+        if segment.start > start || segment.end < end {
+            return None;
+        }
+        let original = segment.original + start - segment.start;
+        Some(original..original + end - start)
+    }
+
+    /// The range in the code from where `start` to where `end` of the preprocessed text were copied
+    /// from. Unlike `original_range`, the text in between can come from anywhere, as long as both
+    /// ends are mapped and still in order.
+    pub fn original_span(&self, start: usize, end: usize) -> Option<Range<usize>> {
+        if start == end {
+            return self.original_range(start, end);
+        }
+        let first = self.original_range(start, start + 1)?;
+        let last = self.original_range(end - 1, end)?;
+        (first.start <= last.end).then_some(first.start..last.end)
+    }
+
+    /// Ranges of the code, `code_len` bytes long, that didn't make it into the preprocessed text
+    pub fn unmapped(&self, code_len: usize) -> Vec<Range<usize>> {
+        let mut output = vec![];
+        let mut position = 0;
+        for segment in &self.segments {
+            if segment.original > position {
+                output.push(position..segment.original);
+            }
+            position = segment.original + segment.end - segment.start;
+        }
+        if position < code_len {
+            output.push(position..code_len);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn source_map(code: &str) -> (SyntaxTree, SourceMap) {
+        let (tree, _) = sv_parser::parse_sv_str(
+            code,
+            PathBuf::from("test.sv"),
+            &HashMap::new(),
+            &Vec::<PathBuf>::new(),
+            false,
+            false,
+        )
+        .unwrap();
+        let source_map = SourceMap::new(&tree, code, Path::new("test.sv"));
+        (tree, source_map)
+    }
+
+    fn mapped_identifiers(tree: &SyntaxTree, source_map: &SourceMap, code: &str) -> Vec<String> {
+        let mut output = vec![];
+        for node in tree {
+            if let RefNode::SimpleIdentifier(identifier) = node {
+                let locate = &identifier.nodes.0;
+                if let Some(range) =
+                    source_map.original_range(locate.offset, locate.offset + locate.len)
+                {
+                    output.push(code[range].to_string());
+                }
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn source_map_skips_inactive_branches() {
+        let code = "module m;\n`ifdef A\nlogic a;\n`else\nlogic b;\n`endif\nlogic c;\nendmodule\n";
+        let (tree, source_map) = source_map(code);
+
+        assert_eq!(
+            mapped_identifiers(&tree, &source_map, code),
+            ["m", "b", "c"]
+        );
+
+        let unmapped = source_map
+            .unmapped(code.len())
+            .into_iter()
+            .map(|range| code[range].trim().to_string())
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(unmapped, ["`ifdef A\nlogic a;\n`else", "`endif"]);
+    }
+
+    #[test]
+    fn source_map_does_not_map_macro_expansions() {
+        let code = "`define NAME b\nmodule m;\nlogic a, `NAME, c;\nendmodule\n";
+        let (tree, source_map) = source_map(code);
+
+        assert_eq!(
+            mapped_identifiers(&tree, &source_map, code),
+            ["NAME", "m", "a", "c"]
+        );
+    }
+
+    #[test]
+    fn source_map_spans_across_tokens() {
+        let code = "`define NAME b\nmodule m;\nlogic a, `NAME, c;\nendmodule\n";
+        let (tree, source_map) = source_map(code);
+        let keywords = tree
+            .into_iter()
+            .filter_map(|node| match node {
+                RefNode::Locate(locate) => Some(*locate),
+                _ => None,
+            })
+            .filter(|locate| matches!(tree.get_str(locate), Some("module" | "endmodule")))
+            .collect::<Vec<_>>();
+        let (start, end) = (keywords[0].offset, keywords[1].offset + keywords[1].len);
+
+        let range = source_map.original_span(start, end).unwrap();
+        assert_eq!(&code[range], "module m;\nlogic a, `NAME, c;\nendmodule");
+        assert_eq!(source_map.original_range(start, end), None);
+    }
+}
@@ -1,25 +1,27 @@
-use crate::matcher::{BreadcrumbsMatcher, MatchPattern};
-use clap::{Parser, Subcommand};
+use crate::aliases::Aliases;
+use crate::conditions::Conditions;
+use crate::matcher::{parse_pattern, BreadcrumbsMatcher, TryIntoLocate};
+use crate::node_kind::NodeKind;
+use crate::scopes::Scopes;
+use crate::source_map::SourceMap;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::fs;
-use std::path::PathBuf;
-use std::rc::Rc;
-use sv_parser::parse_sv_str;
-use sv_parser::NodeEvent;
-use sv_parser::RefNode;
+use std::ops::Range;
+use sv_parser::{Iter, NodeEvent, RefNode, SyntaxTree};
 use toml::Table;
 
 use anyhow::bail;
 use anyhow::Context;
-use std::cell::RefCell;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub enum SyntaxItemType<'a> {
+pub enum SyntaxItemType {
     Always,
-    //The group to check with, to see if the text we matched is also defined there
-    IfDefined(&'a str),
-    IfDefinedElse(&'a str, &'a str),
+    //Index of the `Conditions` in the matcher deciding, from where the text we matched is also
+    //defined, which group the item goes to
+    Conditional(usize),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -27,25 +29,28 @@ pub struct SyntaxItem<'a> {
     pub group: &'a str,
     pub col_start: usize, //  Treating the whole code as 1 line
     pub col_end: usize,   //  Treating the whole code as 1 line
-    pub syntax_type: SyntaxItemType<'a>,
+    pub syntax_type: SyntaxItemType,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct MatchedSyntaxItem<'a> {
     pub group: &'a str,
     pub line: usize,
-    pub col_start: usize, //  Treating the whole code as 1 line
-    pub col_end: usize,   //  Treating the whole code as 1 line
+    pub col_start: usize,    //  Within the line
+    pub col_end: usize,      //  Within the line
+    pub offset_start: usize, //  Treating the whole code as 1 line
+    pub offset_end: usize,   //  Treating the whole code as 1 line
+    #[serde(rename = "text")]
     pub matched: &'a str,
 }
 
 impl<'a> MatchedSyntaxItem<'a> {
-    fn from_unmatched(item: SyntaxItem<'a>, code: &'a str) -> Vec<Self> {
-        let line_vec =
-            MatchedSyntaxItem::range_to_lines_cols(item.col_start, item.col_end - 1, &code);
+    /// Splits `item`, given as offsets into `code`, into one item per line it covers
+    pub fn from_unmatched(item: SyntaxItem<'a>, code: &'a str) -> Vec<Self> {
+        let line_vec = MatchedSyntaxItem::range_to_lines_cols(item.col_start, item.col_end, code);
 
         assert!(
-            line_vec.len() > 0,
+            !line_vec.is_empty() || code[item.col_start..item.col_end].trim_end().is_empty(),
             "Must return more lines than 0? {}, {}",
             item.col_start,
             item.col_end
@@ -53,69 +58,139 @@ impl<'a> MatchedSyntaxItem<'a> {
 
         let mut out = vec![];
 
-        for (line_number, col_start, col_end) in line_vec {
-            let matched = &code.lines().nth(line_number).unwrap()[col_start..col_end];
-
+        for (line_number, line_start, col_start, col_end) in line_vec {
             out.push(Self {
                 group: item.group,
-                matched,
+                matched: &code[line_start + col_start..line_start + col_end],
                 col_start,
                 col_end,
+                offset_start: line_start + col_start,
+                offset_end: line_start + col_end,
                 line: line_number + 1,
             });
         }
-        return out;
+        out
     }
 
+    /// The `(line index, line offset, col_start, col_end)` of every line `start..end` covers,
+    /// leaving out the line endings, `\n` or `\r\n`
     fn range_to_lines_cols(
-        start_col: usize,
-        end_col: usize,
+        start: usize,
+        end: usize,
         code: &str,
-    ) -> Vec<(usize, usize, usize)> {
-        let mut output: Vec<(usize, usize, usize)> = vec![];
-        let mut found_start = false;
-        let mut char_count = 0;
-        for (index, line) in code.lines().enumerate() {
-            let line_len = line.len();
-            if !found_start {
-                if line_len + char_count > end_col {
-                    return vec![(index, start_col - char_count, end_col + 1 - char_count)];
-                }
-                if line_len + char_count > start_col {
-                    found_start = true;
-                    output.push((index, start_col - char_count, line_len));
-                }
-            } else {
-                if line_len + char_count > end_col {
-                    output.push((index, 0, end_col + 1 - char_count));
-                    return output;
-                } else {
-                    output.push((index, 0, line_len));
+    ) -> Vec<(usize, usize, usize, usize)> {
+        let mut output: Vec<(usize, usize, usize, usize)> = vec![];
+        let mut line_start = 0;
+        for (index, line) in code.split_inclusive('\n').enumerate() {
+            if line_start >= end {
+                break;
+            }
+            let content = line.strip_suffix('\n').unwrap_or(line);
+            let content = content.strip_suffix('\r').unwrap_or(content);
+
+            if start < line_start + line.len() {
+                let col_start = start.saturating_sub(line_start).min(content.len());
+                let col_end = (end - line_start).min(content.len());
+                if col_start < col_end {
+                    output.push((index, line_start, col_start, col_end));
                 }
             }
-            char_count += line_len + 1;
+            line_start += line.len();
         }
-        return output;
+        output
+    }
+
+    /// Converts the byte columns of the item into `encoding`. `code` has to be the code the item
+    /// was made from.
+    pub fn encode_columns(&mut self, code: &str, encoding: ColumnEncoding) {
+        let line_start = self.offset_start - self.col_start;
+        self.col_start = encoding.units(&code[line_start..self.offset_start]);
+        self.col_end = encoding.units(&code[line_start..self.offset_end]);
     }
 }
 
+/// What the columns given to the plugin count. Tabs are a single unit in all of them.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ColumnEncoding {
+    /// Bytes of UTF-8, as nvim counts columns
+    #[default]
+    Utf8,
+    /// UTF-16 code units, as LSP counts columns by default
+    Utf16,
+    /// Unicode scalar values
+    Codepoints,
+}
+
+impl ColumnEncoding {
+    /// How many units `text` is long in this encoding
+    pub fn units(self, text: &str) -> usize {
+        match self {
+            ColumnEncoding::Utf8 => text.len(),
+            ColumnEncoding::Utf16 => text.encode_utf16().count(),
+            ColumnEncoding::Codepoints => text.chars().count(),
+        }
+    }
+}
+
+/// What happens to code more than one group matched
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Overlaps {
+    /// Only the group with the highest priority is kept
+    #[default]
+    Winner,
+    /// Every group is kept, as separate items over the same range, lowest priority first
+    Stack,
+}
+
+/// What a pattern adds items to
+#[derive(Debug, Clone, Copy)]
+struct PatternGroup<'a> {
+    group: &'a str,
+    syntax_type: SyntaxItemType,
+    //Whether the pattern adds a single item spanning the node it ends on, instead of one for every
+    //`Locate` it matches
+    region: bool,
+}
+
 pub struct SyntaxMatcher<'a> {
-    //Used to lookup for variable definitions and so on
-    syntax: Rc<RefCell<Vec<SyntaxItem<'a>>>>,
-    matchers: Vec<BreadcrumbsMatcher<'a>>,
-    toml: &'a Table,
+    //Used to lookup for variable definitions and so on, with the scope each item is in
+    syntax: Vec<(SyntaxItem<'a>, usize)>,
+    scopes: Scopes,
+    matcher: BreadcrumbsMatcher,
+    //What each of the matcher's patterns adds items to
+    pattern_groups: Vec<PatternGroup<'a>>,
     colors: HashMap<&'a str, &'a str>,
+    //Every group an item can end up in, in the order they are configured
+    groups: Vec<&'a str>,
+    //The `priority` of every group in `groups`, groups only items of other groups go to have that
+    //of the first group naming them
+    priorities: HashMap<&'a str, i64>,
+    //The groups of region items, along with the groups their items can go to
+    regions: HashSet<&'a str>,
+    //What `SyntaxItemType::Conditional` items are resolved with
+    conditions: Vec<Conditions<'a>>,
+    overlaps: Overlaps,
 }
 
 impl<'a> SyntaxMatcher<'a> {
     pub fn from_toml(toml: &'a Table) -> anyhow::Result<Self> {
-        let syntax = Rc::new(RefCell::new(vec![]));
-
         let mut defined_groups: HashSet<&str> = HashSet::new();
         let mut used_groups: HashSet<&str> = HashSet::new();
 
-        let mut groups: Vec<(Vec<Vec<MatchPattern>>, &str, SyntaxItemType)> = vec![];
+        let mut matcher = BreadcrumbsMatcher::new();
+        //Each group, with how many patterns it added to the matcher
+        let mut groups: Vec<(usize, PatternGroup)> = vec![];
         let mut colors = HashMap::new();
+        let mut priorities = HashMap::new();
+        let mut conditions = vec![];
+
+        let aliases = match toml.get("aliases") {
+            Some(toml::Value::Table(table)) => {
+                Aliases::from_toml(table).context("Invalid \"aliases\" table")?
+            }
+            Some(other) => bail!("Found {other} as \"aliases\", expected a table"),
+            None => Aliases::default(),
+        };
 
         for (name, content) in toml.iter() {
             //Anything starting with 'denshi' is a highlight group
@@ -131,28 +206,38 @@ impl<'a> SyntaxMatcher<'a> {
                 }
                 continue;
             }
+            if name == "aliases" {
+                continue;
+            }
             match content {
                 toml::Value::Table(ref table_inner) => {
-                    let mut filters = vec![];
+                    let mut filters = 0;
                     if let Some(toml::Value::Array(pattern_list)) = table_inner.get("patterns") {
-                        if pattern_list.len() == 0 {
+                        if pattern_list.is_empty() {
                             bail!("Length of \'patterns\' in {name} can't be 0");
                         }
                         for k in pattern_list.iter() {
                             if let toml::Value::String(pattern) = k {
-                                let mut filter_match = vec![];
-                                for k in pattern.split(" ").into_iter() {
-                                    let first_char = &k[0..1]; //.expect("Syntax identifier should be longer than 0");
-                                    if first_char == "^" {
-                                        filter_match.push(MatchPattern::NotMatches(k[1..].into()));
+                                let context =
+                                    || format!("Invalid pattern \"{pattern}\" in group {name}");
+                                let expanded = aliases.expand(pattern).with_context(context)?;
+                                let context = || {
+                                    if expanded == *pattern {
+                                        context()
                                     } else {
-                                        filter_match.push(MatchPattern::Matches(k.into()));
+                                        format!(
+                                            "Invalid pattern \"{pattern}\", \"{expanded}\" \
+                                             without aliases, in group {name}"
+                                        )
                                     }
-                                }
+                                };
+                                let filter_match =
+                                    parse_pattern(&expanded).with_context(context)?;
                                 if filter_match.is_empty() {
                                     bail!("Match pattern is empty for group {name}");
                                 }
-                                filters.push(filter_match);
+                                matcher.add_pattern(filter_match).with_context(context)?;
+                                filters += 1;
                             } else {
                                 bail!("Found {k:?} in pattern {name}");
                             }
@@ -160,24 +245,36 @@ impl<'a> SyntaxMatcher<'a> {
                     } else {
                         bail!("\'patterns\' array not found in group {name}");
                     }
-                    let syntax_type = match table_inner.get("ifDefined") {
-                        Some(toml::Value::String(pattern)) => {
-                            used_groups.insert(pattern);
-                            if let Some(toml::Value::String(other_group)) =
-                                table_inner.get("orElse")
-                            {
-                                SyntaxItemType::IfDefinedElse(pattern, other_group)
-                            } else {
-                                SyntaxItemType::IfDefined(pattern)
-                            }
+                    let syntax_type = match Conditions::from_toml(name, table_inner)? {
+                        Some(group_conditions) => {
+                            used_groups.extend(group_conditions.predicates());
+                            conditions.push(group_conditions);
+                            SyntaxItemType::Conditional(conditions.len() - 1)
                         }
-                        _ => SyntaxItemType::Always,
+                        None => SyntaxItemType::Always,
+                    };
+
+                    let region = match table_inner.get("region") {
+                        Some(toml::Value::Boolean(region)) => *region,
+                        Some(other) => bail!("Found {other} as \'region\' of group {name}"),
+                        None => false,
+                    };
+
+                    let priority = match table_inner.get("priority") {
+                        Some(toml::Value::Integer(priority)) => *priority,
+                        Some(other) => bail!("Found {other} as \'priority\' of group {name}"),
+                        None => 0,
                     };
+                    priorities.insert(name.as_str(), priority);
 
                     defined_groups.insert(name);
 
-                    let filter_group = (filters, name.as_ref(), syntax_type);
-                    groups.push(filter_group);
+                    let filter_group = PatternGroup {
+                        group: name.as_ref(),
+                        syntax_type,
+                        region,
+                    };
+                    groups.push((filters, filter_group));
                 }
                 other => bail!("Found {other:?} in toml"),
             }
@@ -185,37 +282,63 @@ impl<'a> SyntaxMatcher<'a> {
 
         for used_group in used_groups.iter() {
             if !defined_groups.contains(used_group) {
-                bail!("Group used in IfDefined \"{used_group}\" does not exist");
+                bail!("Group used in a condition \"{used_group}\" does not exist");
             }
         }
 
-        let mut matchers = vec![];
-        for group in groups {
-            let inner_vec = Rc::clone(&syntax);
-            let callback = move |locate: &sv_parser::Locate| {
-                inner_vec.borrow_mut().push(SyntaxItem {
-                    group: group.1,
-                    col_start: locate.offset,
-                    col_end: locate.offset + locate.len,
-                    syntax_type: group.2,
-                });
-            };
-
-            for pattern in group.0 {
-                let bc = BreadcrumbsMatcher::new(pattern, Box::new(callback.clone()));
-                matchers.push(bc)
+        let mut group_names = vec![];
+        let mut regions = HashSet::new();
+        for (_, pattern_group) in &groups {
+            group_names.push(pattern_group.group);
+            if pattern_group.region {
+                regions.insert(pattern_group.group);
+            }
+            if let SyntaxItemType::Conditional(index) = pattern_group.syntax_type {
+                for other_group in conditions[index].targets() {
+                    group_names.push(other_group);
+                    let priority = priorities[pattern_group.group];
+                    priorities.entry(other_group).or_insert(priority);
+                    if pattern_group.region {
+                        regions.insert(other_group);
+                    }
+                }
             }
         }
+        let group_names = group_names.into_iter().unique().collect();
+
+        let mut pattern_groups = vec![];
+        for (pattern_count, pattern_group) in groups {
+            pattern_groups.extend(std::iter::repeat_n(pattern_group, pattern_count));
+        }
         Ok(Self {
-            toml,
-            matchers,
-            syntax,
+            matcher,
+            pattern_groups,
+            syntax: vec![],
+            scopes: Scopes::default(),
             colors,
+            groups: group_names,
+            priorities,
+            regions,
+            conditions,
+            overlaps: Overlaps::default(),
         })
     }
 
+    pub fn set_overlaps(&mut self, overlaps: Overlaps) {
+        self.overlaps = overlaps;
+    }
+
+    pub fn get_groups(&self) -> &[&'a str] {
+        &self.groups
+    }
+
+    /// Whether items of `group` are regions, spanning whole nodes
+    pub fn is_region(&self, group: &str) -> bool {
+        self.regions.contains(group)
+    }
+
     pub fn get_colors(&self) -> HashMap<&str, &str> {
-        return self.colors.clone();
+        self.colors.clone()
     }
 
     pub fn get_colors_as_ansi(&self) -> anyhow::Result<HashMap<String, String>> {
@@ -224,8 +347,8 @@ impl<'a> SyntaxMatcher<'a> {
         for (group, color_str) in &self.colors {
             let mut codes = vec![];
 
-            for command in color_str.split(" ") {
-                let mut split = command.split("=");
+            for command in color_str.split(' ') {
+                let mut split = command.split('=');
                 let cmd = split.next().context(format!(
                     "Expected group {group}, command {command} to contain an '='"
                 ))?;
@@ -240,7 +363,7 @@ impl<'a> SyntaxMatcher<'a> {
                     }
                     "cterm" => codes.push(
                         value
-                            .split(",")
+                            .split(',')
                             .map(|cterm_code| {
                                 Ok(match cterm_code {
                                     "bold" => "1",
@@ -252,7 +375,12 @@ impl<'a> SyntaxMatcher<'a> {
                             .collect::<Result<Vec<_>, _>>()?
                             .join(";"),
                     ),
-                    "guifg" => (),
+                    "ctermbg" => {
+                        codes.push("48".to_string());
+                        codes.push("5".to_string());
+                        codes.push(value.to_string());
+                    }
+                    "guifg" | "guibg" => (),
                     patt => bail!("Unknown command {patt}"),
                 }
             }
@@ -262,80 +390,565 @@ impl<'a> SyntaxMatcher<'a> {
         Ok(output)
     }
 
-    pub fn enter(&mut self, node: &RefNode) {
-        for m in &mut self.matchers {
-            m.enter(node);
+    /// Steps the matcher into `node`, which is part of `tree`. Every pattern matching a `Locate`
+    /// adds an item for its group, and every region pattern ending on `node` one spanning it.
+    pub fn enter(&mut self, node: &RefNode, tree: &SyntaxTree) {
+        let locate = node.try_into_locate();
+        let text = locate.and_then(|locate| tree.get_str(locate));
+        self.matcher.enter(NodeKind::of(node), text);
+        self.scopes.enter(node, tree);
+        let scope = self.scopes.current();
+
+        if let Some(locate) = locate {
+            for pattern in self.matcher.matched() {
+                let pattern_group = self.pattern_groups[pattern];
+                if !pattern_group.region {
+                    let item = SyntaxItem {
+                        group: pattern_group.group,
+                        col_start: locate.offset,
+                        col_end: locate.offset + locate.len,
+                        syntax_type: pattern_group.syntax_type,
+                    };
+                    self.syntax.push((item, scope));
+                }
+            }
         }
-    }
 
-    pub fn leave(&mut self, node: &RefNode) {
-        for m in &mut self.matchers {
-            m.leave(node);
+        let mut span = None;
+        for pattern in self.matcher.completed() {
+            let pattern_group = self.pattern_groups[*pattern];
+            if !pattern_group.region {
+                continue;
+            }
+            let Some(range) = span.get_or_insert_with(|| node_span(node)).clone() else {
+                continue;
+            };
+            let item = SyntaxItem {
+                group: pattern_group.group,
+                col_start: range.start,
+                col_end: range.end,
+                syntax_type: pattern_group.syntax_type,
+            };
+            self.syntax.push((item, scope));
         }
     }
 
-    pub fn compute(self, code: &'a str) -> Vec<MatchedSyntaxItem<'a>> {
+    pub fn leave(&mut self) {
+        self.matcher.leave();
+        self.scopes.leave();
+    }
+
+    /// Resolves everything matched since the last call against `code`. The matched items are
+    /// drained, so the same matcher can be driven over another tree afterwards.
+    ///
+    /// `source_map` maps offsets in the text that was parsed back to offsets in `code`, items it
+    /// can't map are dropped.
+    pub fn compute<'c>(
+        &mut self,
+        code: &'c str,
+        source_map: &SourceMap,
+    ) -> Vec<MatchedSyntaxItem<'c>>
+    where
+        'a: 'c,
+    {
+        let items = self.compute_items(code, source_map);
+        self.resolve(items, code)
+    }
+
+    /// `compute`, without resolving the overlaps yet, so that items found some other way can be
+    /// added before `resolve`.
+    ///
+    /// Conditional items only count as defined in a group when the definition is in their own
+    /// scope, one around it, or a package one of those imports it from.
+    pub fn compute_items(&mut self, code: &str, source_map: &SourceMap) -> Vec<SyntaxItem<'a>> {
         let mut requiring_defs = vec![];
-        let mut output_str: Vec<MatchedSyntaxItem> = vec![];
+        let mut output_str: Vec<SyntaxItem> = vec![];
 
-        //Need to drop matchers here, as this drops all the closures, which have refs to the Rc
-        drop(self.matchers);
+        self.matcher.reset();
+        let scopes = std::mem::take(&mut self.scopes);
 
-        let current_list = Rc::try_unwrap(self.syntax)
-            .expect("Should have no references to this RC now")
-            .into_inner()
+        let current_list = std::mem::take(&mut self.syntax)
             .into_iter()
+            .filter_map(|(item, scope)| {
+                let range = source_map.original_span(item.col_start, item.col_end)?;
+                let item = SyntaxItem {
+                    col_start: range.start,
+                    col_end: range.end,
+                    ..item
+                };
+                Some((item, scope))
+            })
             .unique();
 
-        let mut keyword_map: HashMap<&str, HashSet<&str>> = HashMap::new();
+        //The group, text and scope of every definition
+        let mut keyword_map: HashSet<(&str, &str, usize)> = HashSet::new();
 
-        for item in current_list {
+        for (item, scope) in current_list {
             let matched = &code[item.col_start..item.col_end];
             if let SyntaxItemType::Always = item.syntax_type {
-                keyword_map
-                    .entry(item.group)
-                    .or_insert(HashSet::new())
-                    .insert(matched);
-                output_str.append(&mut MatchedSyntaxItem::from_unmatched(item, code));
+                keyword_map.insert((item.group, matched, scope));
+                output_str.push(item);
             } else {
-                requiring_defs.push(item);
+                requiring_defs.push((item, scope));
             }
         }
 
-        for mut item in requiring_defs {
+        for (mut item, scope) in requiring_defs {
             let matched_str = &code[item.col_start..item.col_end];
-            match item.syntax_type {
-                SyntaxItemType::IfDefined(predicate_group) => {
-                    if keyword_map
-                        .get(predicate_group)
-                        .map_or(false, |x| x.contains(matched_str))
-                    {
-                        output_str.append(&mut MatchedSyntaxItem::from_unmatched(item, code))
-                    }
+            let defined_in = |predicate_group| {
+                scopes
+                    .visible_from(scope, matched_str)
+                    .any(|scope| keyword_map.contains(&(predicate_group, matched_str, scope)))
+            };
+            let SyntaxItemType::Conditional(index) = item.syntax_type else {
+                unreachable!()
+            };
+            if let Some(group) = self.conditions[index].group(defined_in) {
+                item.group = group;
+                output_str.push(item)
+            }
+        }
+
+        output_str
+    }
+
+    /// Resolves the overlaps between `items`, given as offsets into `code`, and splits them into
+    /// lines
+    pub fn resolve<'c>(
+        &self,
+        items: Vec<SyntaxItem<'c>>,
+        code: &'c str,
+    ) -> Vec<MatchedSyntaxItem<'c>> {
+        self.resolve_overlaps(items)
+            .into_iter()
+            .flat_map(|item| MatchedSyntaxItem::from_unmatched(item, code))
+            .collect()
+    }
+
+    /// Splits `items` at every place one of them starts or ends, and decides which groups each
+    /// piece goes to. The group with the highest `priority` wins, then the one whose item is
+    /// shorter, then the one listed first in `get_groups`. Groups missing from it, like that of
+    /// parse errors, win those ties. Pieces next to each other that end up with the same items are
+    /// joined back together.
+    ///
+    /// The pieces come out in order, with every group of a piece in order of the above, lowest
+    /// first, so applying them in order leaves the winner on top.
+    ///
+    /// Region items aren't split, they are kept whole and come before the pieces they overlap.
+    fn resolve_overlaps<'i>(&self, items: Vec<SyntaxItem<'i>>) -> Vec<SyntaxItem<'i>> {
+        let (regions, items): (Vec<_>, Vec<_>) = items
+            .into_iter()
+            .filter(|item| item.col_start < item.col_end)
+            .sorted_by_key(|item| item.col_start)
+            .partition(|item| self.regions.contains(item.group));
+        let rank = |item: &SyntaxItem| {
+            let priority = self.priorities.get(item.group).copied().unwrap_or_default();
+            let position = self.groups.iter().position(|group| *group == item.group);
+            (
+                priority,
+                Reverse(item.col_end - item.col_start),
+                Reverse(position),
+            )
+        };
+
+        let bounds = items
+            .iter()
+            .flat_map(|item| [item.col_start, item.col_end])
+            .sorted()
+            .dedup()
+            .collect::<Vec<_>>();
+
+        let mut pieces: Vec<(Range<usize>, Vec<usize>)> = vec![];
+        let mut active: Vec<usize> = vec![];
+        let mut next = 0;
+        for piece in bounds.windows(2) {
+            let (start, end) = (piece[0], piece[1]);
+            active.retain(|index| items[*index].col_end > start);
+            while items.get(next).is_some_and(|item| item.col_start <= start) {
+                active.push(next);
+                next += 1;
+            }
+
+            let mut stack = active
+                .iter()
+                .copied()
+                .sorted_by_key(|index| rank(&items[*index]))
+                .collect::<Vec<_>>();
+            //Only the highest ranked item of every group counts
+            let mut seen = HashSet::new();
+            stack.reverse();
+            stack.retain(|index| seen.insert(items[*index].group));
+            stack.reverse();
+            if self.overlaps == Overlaps::Winner {
+                stack.drain(..stack.len().saturating_sub(1));
+            }
+            if stack.is_empty() {
+                continue;
+            }
+
+            match pieces.last_mut() {
+                Some((range, last)) if range.end == start && *last == stack => range.end = end,
+                _ => pieces.push((start..end, stack)),
+            }
+        }
+
+        let mut output = vec![];
+        for (range, stack) in pieces {
+            for index in stack {
+                output.push(SyntaxItem {
+                    col_start: range.start,
+                    col_end: range.end,
+                    ..items[index].clone()
+                });
+            }
+        }
+        regions
+            .into_iter()
+            .merge_by(output, |region, piece| region.col_start <= piece.col_start)
+            .collect()
+    }
+}
+use itertools::Itertools;
+
+/// The code `node` spans, from the start of its first `Locate` to the end of its last one. Whitespace
+/// and comments below it are left out, unless `node` is whitespace itself.
+fn node_span(node: &RefNode) -> Option<Range<usize>> {
+    let mut span: Option<Range<usize>> = None;
+    let mut whitespace_depth = 0usize;
+    //Skipping the node itself, it's only whitespace below it that is left out
+    for event in Iter::new(vec![node.clone()].into()).event().skip(1) {
+        match event {
+            NodeEvent::Enter(RefNode::WhiteSpace(_)) => whitespace_depth += 1,
+            NodeEvent::Leave(RefNode::WhiteSpace(_)) => {
+                whitespace_depth = whitespace_depth.saturating_sub(1)
+            }
+            NodeEvent::Enter(RefNode::Locate(locate)) if whitespace_depth == 0 => {
+                let start = span.as_ref().map_or(locate.offset, |span| span.start);
+                span = Some(start..locate.offset + locate.len);
+            }
+            _ => (),
+        }
+    }
+    span
+}
+
+/// The group, line and text of every item the config in `toml` gives `code`
+#[cfg(test)]
+pub fn test_items<'c>(code: &'c str, toml: &str) -> Vec<(&'c str, usize, &'c str)> {
+    //The groups borrow the config, which is leaked so that they can outlive this
+    let config = Box::leak(Box::new(toml.parse::<Table>().unwrap()));
+    let mut matcher = SyntaxMatcher::from_toml(config).unwrap();
+
+    let options = crate::parse::ParseOptions::new(&[], &[]);
+    let tree = crate::parse::parse_code(code, "test.sv", &options).unwrap();
+    for event in tree.into_iter().event() {
+        match event {
+            NodeEvent::Enter(ref node) => matcher.enter(node, &tree),
+            NodeEvent::Leave(_) => matcher.leave(),
+        }
+    }
+    let source_map = SourceMap::new(&tree, code, std::path::Path::new("test.sv"));
+    matcher
+        .compute(code, &source_map)
+        .into_iter()
+        .map(|item| (item.group, item.line, item.matched))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matched<'a>(code: &'a str, col_start: usize, col_end: usize) -> Vec<MatchedSyntaxItem<'a>> {
+        let item = SyntaxItem {
+            group: "denshiComment",
+            col_start,
+            col_end,
+            syntax_type: SyntaxItemType::Always,
+        };
+        MatchedSyntaxItem::from_unmatched(item, code)
+    }
+
+    #[test]
+    fn from_unmatched_splits_crlf_lines() {
+        let code = "a\r\n/* é\r\n*/ b\r\n";
+        let items = matched(code, 3, 12);
+
+        let lines = items
+            .iter()
+            .map(|item| (item.line, item.col_start, item.col_end, item.matched))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [(2, 0, 5, "/* é"), (3, 0, 2, "*/")]);
+        assert_eq!((items[1].offset_start, items[1].offset_end), (10, 12));
+    }
+
+    #[test]
+    fn encode_columns_counts_units() {
+        let code = "// é😀\tx\n";
+        let start = code.find('x').unwrap();
+        let columns = [
+            ColumnEncoding::Utf8,
+            ColumnEncoding::Utf16,
+            ColumnEncoding::Codepoints,
+        ]
+        .map(|encoding| {
+            let mut item = matched(code, start, start + 1).remove(0);
+            item.encode_columns(code, encoding);
+            (item.col_start, item.col_end)
+        });
+
+        assert_eq!(columns, [(10, 11), (7, 8), (6, 7)]);
+    }
+
+    #[test]
+    fn from_toml_rejects_unknown_node_kinds() {
+        let toml =
+            "[denshiModuleIdentifier]\npatterns = [\"ModuleIdentifer ^WhiteSpace Locate\"]\n"
+                .parse::<Table>()
+                .unwrap();
+        let Err(error) = SyntaxMatcher::from_toml(&toml) else {
+            panic!("Expected the misspelled node kind to be rejected");
+        };
+
+        assert_eq!(
+            format!("{error:#}"),
+            "Invalid pattern \"ModuleIdentifer ^WhiteSpace Locate\" in group \
+             denshiModuleIdentifier: Unknown node kind \"ModuleIdentifer\", did you mean \
+             \"ModuleIdentifier\"?"
+        );
+    }
+
+    /// Times driving the default config over test.sv, the patterns alone and with everything
+    /// else `enter` does, the fastest of a few tries. Run with
+    /// `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn matcher_timing() {
+        let code = std::fs::read_to_string("test.sv").unwrap();
+        let config = crate::config::load(None, None).unwrap();
+        let mut matcher = SyntaxMatcher::from_toml(&config).unwrap();
+        let options = crate::parse::ParseOptions::new(&[], &[]);
+        let tree = crate::parse::parse_code(&code, "test.sv", &options).unwrap();
+        let events = tree.into_iter().event().collect::<Vec<_>>();
+        let nodes = events.len() as u32 / 2;
+        let runs = 1000;
+
+        let time = |run: &mut dyn FnMut()| {
+            let tries = (0..5).map(|_| {
+                let start = std::time::Instant::now();
+                for _ in 0..runs {
+                    run();
                 }
-                SyntaxItemType::IfDefinedElse(predicate_group, other_group) => {
-                    if !keyword_map
-                        .get(predicate_group)
-                        .map_or(false, |x| x.contains(matched_str))
-                    {
-                        item.group = other_group
+                start.elapsed() / (runs * nodes)
+            });
+            tries.min().unwrap()
+        };
+        let patterns = time(&mut || {
+            for event in &events {
+                match event {
+                    NodeEvent::Enter(node) => {
+                        let text = node
+                            .try_into_locate()
+                            .and_then(|locate| tree.get_str(locate));
+                        matcher.matcher.enter(NodeKind::of(node), text);
                     }
-
-                    output_str.append(&mut MatchedSyntaxItem::from_unmatched(item, code))
+                    NodeEvent::Leave(_) => matcher.matcher.leave(),
                 }
-                SyntaxItemType::Always => unreachable!(),
             }
-        }
+        });
+        let enter = time(&mut || {
+            for event in &events {
+                match event {
+                    NodeEvent::Enter(node) => matcher.enter(node, &tree),
+                    NodeEvent::Leave(_) => matcher.leave(),
+                }
+            }
+            matcher.syntax.clear();
+            matcher.scopes = Scopes::default();
+        });
+        println!("{nodes} nodes, per node: {patterns:?} matching patterns, {enter:?} in enter");
+    }
 
-        //Does all the println b
-        /*
-        println!(
-            "{} {line} {col_start} {col_end} {}",
-            cl_group, matched_chars
+    #[test]
+    fn groups_keep_the_order_they_are_configured_in() {
+        let toml = "[denshiKeyword]\npatterns = [\"Keyword Locate\"]\n\
+                    [denshiVariable]\npatterns = [\"VariableIdentifier Locate\"]\n\
+                    [denshiIdentifier]\npatterns = [\"HierarchicalIdentifier Locate\"]\n\
+                    ifDefined = \"denshiVariable\"\norElse = \"denshiUndefined\"\n\
+                    [denshiComment]\npatterns = [\"Comment Locate\"]\n"
+            .parse::<Table>()
+            .unwrap();
+        let matcher = SyntaxMatcher::from_toml(&toml).unwrap();
+
+        assert_eq!(
+            matcher.get_groups(),
+            [
+                "denshiKeyword",
+                "denshiVariable",
+                "denshiIdentifier",
+                "denshiUndefined",
+                "denshiComment"
+            ]
         );
+    }
 
-        */
-        return output_str;
+    #[test]
+    fn region_groups_span_whole_nodes() {
+        let code = "module m;\nalways_comb begin // starts\n  a = b;\nend // ends\nendmodule\n";
+        let toml = "[denshiAlwaysRegion]\npatterns = [\"AlwaysConstruct\"]\nregion = true\n\
+                    [denshiAlways]\npatterns = [\"AlwaysKeyword Keyword ^WhiteSpace Locate\"]\n";
+        let items = test_items(code, toml);
+
+        assert_eq!(
+            items,
+            [
+                ("denshiAlwaysRegion", 2, "always_comb begin // starts"),
+                ("denshiAlwaysRegion", 3, "  a = b;"),
+                ("denshiAlwaysRegion", 4, "end"),
+                ("denshiAlways", 2, "always_comb"),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_matches_covers_the_whole_gap() {
+        let code = "module m;\nalways_ff @(posedge clk) q[0] <= (a + b) & c;\nendmodule\n";
+        //The pattern that used to carry a FIXME for the matcher
+        let toml = "[denshiAssignmentSymbol]\npatterns = [\"AlwaysConstruct NonblockingAssignment \
+                    ^Expression Symbol ^WhiteSpace Locate\"]\n";
+        let symbols = test_items(code, toml)
+            .into_iter()
+            .map(|(_, _, text)| text)
+            .collect::<Vec<_>>();
+
+        //The brackets of the lvalue aren't in an expression, the symbols on the right are
+        assert_eq!(symbols, ["[", "]", "<="]);
+    }
+
+    #[test]
+    fn overlaps_resolve_by_priority() {
+        let toml = "[denshiRegion]\npatterns = [\"AlwaysConstruct\"]\npriority = -1\n\
+                    [denshiKeyword]\npatterns = [\"Keyword Locate\"]\n\
+                    [denshiAlways]\npatterns = [\"AlwaysKeyword Locate\"]\npriority = 1\n"
+            .parse::<Table>()
+            .unwrap();
+        let mut matcher = SyntaxMatcher::from_toml(&toml).unwrap();
+        let item = |group, col_start, col_end| SyntaxItem {
+            group,
+            col_start,
+            col_end,
+            syntax_type: SyntaxItemType::Always,
+        };
+        let items = vec![
+            item("denshiKeyword", 2, 4),
+            item("denshiRegion", 0, 10),
+            item("denshiAlways", 2, 4),
+            item("denshiKeyword", 6, 8),
+        ];
+        let resolve = |matcher: &SyntaxMatcher, items: &Vec<SyntaxItem<'static>>| {
+            matcher
+                .resolve_overlaps(items.clone())
+                .into_iter()
+                .map(|item| (item.group, item.col_start, item.col_end))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            resolve(&matcher, &items),
+            [
+                ("denshiRegion", 0, 2),
+                ("denshiAlways", 2, 4),
+                ("denshiRegion", 4, 6),
+                ("denshiKeyword", 6, 8),
+                ("denshiRegion", 8, 10),
+            ]
+        );
+
+        matcher.set_overlaps(Overlaps::Stack);
+        assert_eq!(
+            resolve(&matcher, &items),
+            [
+                ("denshiRegion", 0, 2),
+                ("denshiRegion", 2, 4),
+                ("denshiKeyword", 2, 4),
+                ("denshiAlways", 2, 4),
+                ("denshiRegion", 4, 6),
+                ("denshiRegion", 6, 8),
+                ("denshiKeyword", 6, 8),
+                ("denshiRegion", 8, 10),
+            ]
+        );
+
+        //Groups the config doesn't have, like that of parse errors, win ties
+        matcher.set_overlaps(Overlaps::Winner);
+        let items = vec![item("denshiKeyword", 6, 8), item("denshiParseError", 6, 8)];
+        assert_eq!(resolve(&matcher, &items), [("denshiParseError", 6, 8)]);
+    }
+
+    #[test]
+    fn if_defined_only_sees_enclosing_scopes() {
+        let code = "module m;\n\
+                    logic a;\n\
+                    always_comb begin : blk\n\
+                    logic b;\n\
+                    if (a) b = a;\n\
+                    end\n\
+                    always_comb a = b;\n\
+                    function void f;\n\
+                    logic c;\n\
+                    if (a) c = a;\n\
+                    endfunction\n\
+                    endmodule\n\
+                    module n;\n\
+                    always_comb c = a;\n\
+                    endmodule\n\
+                    package p;\n\
+                    logic d;\n\
+                    logic e;\n\
+                    endpackage\n\
+                    module k;\n\
+                    import p::d;\n\
+                    always_comb d = e;\n\
+                    always_comb d = p::e;\n\
+                    endmodule\n\
+                    module l;\n\
+                    import p::*;\n\
+                    always_comb d = e;\n\
+                    always_comb d = a;\n\
+                    endmodule\n";
+        let toml = "[denshiVariable]\n\
+                    patterns = [\"VariableDeclAssignment VariableIdentifier ^WhiteSpace Locate\"]\n\
+                    [denshiIdentifier]\n\
+                    patterns = [\"HierarchicalIdentifier ^WhiteSpace Locate\"]\n\
+                    ifDefined = \"denshiVariable\"\n\
+                    orElse = \"denshiUndefined\"\n";
+        let uses = test_items(code, toml)
+            .into_iter()
+            .filter(|(group, _, _)| *group != "denshiVariable")
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            uses,
+            [
+                ("denshiIdentifier", 5, "a"),
+                ("denshiIdentifier", 5, "b"),
+                ("denshiIdentifier", 5, "a"),
+                ("denshiIdentifier", 7, "a"),
+                ("denshiUndefined", 7, "b"),
+                ("denshiIdentifier", 10, "a"),
+                ("denshiIdentifier", 10, "c"),
+                ("denshiIdentifier", 10, "a"),
+                ("denshiUndefined", 14, "c"),
+                ("denshiUndefined", 14, "a"),
+                ("denshiIdentifier", 22, "d"),
+                ("denshiUndefined", 22, "e"),
+                ("denshiIdentifier", 23, "d"),
+                ("denshiIdentifier", 23, "e"),
+                ("denshiIdentifier", 27, "d"),
+                ("denshiIdentifier", 27, "e"),
+                ("denshiIdentifier", 28, "d"),
+                ("denshiUndefined", 28, "a"),
+            ]
+        );
     }
 }
-use itertools::Itertools;